@@ -1,41 +1,459 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use libp2p::{
-    core::upgrade,
+    core::{
+        connection::{ListenerId, PendingConnectionError},
+        network::ConnectionLimits,
+        upgrade,
+    },
     floodsub::{Floodsub, FloodsubEvent, Topic},
     futures::StreamExt,
     identity,
-    mdns::{Mdns, MdnsEvent},
+    mdns::{Mdns, MdnsConfig, MdnsEvent},
     mplex,
+    multiaddr::Protocol,
     noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{NetworkBehaviourEventProcess, Swarm, SwarmBuilder},
+    ping::{Ping, PingConfig, PingEvent, PingFailure, PingSuccess},
+    plaintext::PlainText2Config,
+    swarm::{NetworkBehaviour as _, NetworkBehaviourEventProcess, Swarm, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
-    NetworkBehaviour, PeerId, Transport,
+    Multiaddr, NetworkBehaviour, PeerId, Transport,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use tokio::{fs, io::AsyncBufReadExt, sync::mpsc};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{BufRead, ErrorKind, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::{fs, sync::mpsc, time};
 
-const STORAGE_FILE_PATH: &str = "./songs.json";
+const STORAGE_FILE_NAME: &str = "songs.json";
+const SETTINGS_FILE_NAME: &str = "settings.json";
+const IDENTITY_FILE_NAME: &str = "identity.key";
+const IDENTITY_LOCK_NAME: &str = "identity.lock";
+const EDIT_FILE_NAME: &str = "songs.edit.json";
+const SNAPSHOT_DIR_NAME: &str = "snapshots";
+/// Responses smaller than this are sent as plain JSON, gzip wouldn't save much.
+const COMPRESS_MIN_BYTES: usize = 1024;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Floodsub rejects RPC packets larger than this, including its own framing.
+const FLOODSUB_MAX_PACKET: usize = 2048;
+/// Upper bound for a decompressed message, so a small message can't expand without limit.
+const MAX_INFLATED_BYTES: u64 = 16 * 1024 * 1024;
+const MAX_NICK_LEN: usize = 32;
+const MAX_LYRICS_LEN: usize = 10_000;
+const TOP_ARTISTS: usize = 5;
+const LOCAL_PLATFORM: &str = "local";
+/// Platform recorded for songs saved from peers that didn't record one.
+const PEER_PLATFORM: &str = "peer";
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
 type Songs = Vec<Song>;
 
-static KEYS: Lazy<identity::Keypair> = Lazy::new(|| identity::Keypair::generate_ed25519());
+static KEYS: Lazy<identity::Keypair> = Lazy::new(load_identity);
 static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
-static TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("songs"));
+static CONFIG: Lazy<Config> = Lazy::new(Config::from_env);
+static STORAGE_FILE_PATH: Lazy<PathBuf> = Lazy::new(|| CONFIG.data_dir.join(STORAGE_FILE_NAME));
+/// Serializes read-modify-write cycles of songs saved from background tasks.
+static SAVE_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+/// The songs file as this process last wrote it, so `--watch` doesn't reload its own writes.
+static LAST_WRITTEN: Lazy<std::sync::Mutex<Option<String>>> =
+    Lazy::new(|| std::sync::Mutex::new(None));
+/// Log lines held back while `edit all` has the terminal, `None` when lines print directly.
+static HELD_LOG_LINES: Lazy<std::sync::Mutex<Option<HeldLog>>> =
+    Lazy::new(|| std::sync::Mutex::new(None));
+/// Songs already reported with a bad checksum, so every read doesn't warn again.
+static CHECKSUM_WARNED: Lazy<std::sync::Mutex<HashSet<usize>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashSet::new()));
+/// Songs already reported with an unrecognized explicit value.
+static EXPLICIT_WARNED: Lazy<std::sync::Mutex<HashSet<usize>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashSet::new()));
+static SETTINGS_FILE_PATH: Lazy<PathBuf> = Lazy::new(|| CONFIG.data_dir.join(SETTINGS_FILE_NAME));
+/// Whether song listings use the multi-line `verbose` layout, loaded from settings at startup.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+static IDENTITY_FILE_PATH: Lazy<PathBuf> = Lazy::new(|| CONFIG.data_dir.join(IDENTITY_FILE_NAME));
+static IDENTITY_LOCK_PATH: Lazy<PathBuf> = Lazy::new(|| CONFIG.data_dir.join(IDENTITY_LOCK_NAME));
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Storage file problems that deserve a clearer message than the raw OS error.
+#[derive(Debug)]
+enum StorageError {
+    IsDirectory(&'static Path),
+    PermissionDenied {
+        action: &'static str,
+        path: &'static Path,
+    },
+    Io(std::io::Error),
+}
+
+impl StorageError {
+    fn from_io(e: std::io::Error, action: &'static str, path: &'static Path) -> Self {
+        match e.kind() {
+            ErrorKind::IsADirectory => StorageError::IsDirectory(path),
+            ErrorKind::PermissionDenied => StorageError::PermissionDenied { action, path },
+            _ => StorageError::Io(e),
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::IsDirectory(path) => {
+                write!(f, "{} is a directory: remove or rename it", path.display())
+            }
+            StorageError::PermissionDenied { action, path } => write!(
+                f,
+                "permission denied {} {}: use a writable --data-dir",
+                action,
+                path.display()
+            ),
+            StorageError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Runtime options, read from command line flags first and `SPOTIFY_BLOG_*` env vars second.
+#[derive(Debug)]
+struct Config {
+    /// How many times to report on mdns discovery progress at startup.
+    discovery_retries: u32,
+    /// Interval between startup discovery rounds, each of which sends an extra mdns query.
+    discovery_interval: Duration,
+    /// Broadcast a `LibraryUpdated` hint after every successful `publish song`.
+    auto_announce: bool,
+    /// Broadcast an `Unpublish` for every song made private, so peers drop cached copies.
+    announce_unpublish: bool,
+    /// Ask peers for gzipped responses.
+    compress: bool,
+    /// Request a peer's songs as soon as it announces a library update.
+    auto_refresh: bool,
+    /// How long to collect responses to a `list songs` query before printing them.
+    response_timeout: Duration,
+    /// How many queries may collect responses at the same time.
+    max_queries: usize,
+    /// How many peers' responses a query to all peers collects, unlimited if unset.
+    max_peers_query: Option<usize>,
+    /// Explicit value used when `create song` leaves it out and none was set with `set default explicit`.
+    default_explicit: bool,
+    /// Print each `list songs` response as it arrives instead of one merged list at the end.
+    stream_responses: bool,
+    /// Skip the banner, command menu and discovery progress at startup.
+    quiet: bool,
+    /// Draw local cover images in `show song` on terminals with an image protocol.
+    inline_covers: bool,
+    /// Shown before each command line on interactive terminals, empty to disable.
+    prompt: String,
+    /// Upper bound of the random delay before joining the topic and adding discovered peers.
+    join_jitter: Duration,
+    /// How long an mdns-expired peer stays in the floodsub partial view in case it reappears.
+    expire_grace: Duration,
+    /// Minimum time between removing a peer from the partial view and adding it back.
+    rejoin_cooldown: Duration,
+    /// How often connected peers are pinged to measure latency.
+    ping_interval: Duration,
+    /// How often each peer from `follow` is asked for its songs, at least 30 seconds.
+    follow_interval: Duration,
+    /// Floodsub topics to join at startup, `topic` can switch at runtime.
+    topics: Vec<String>,
+    /// Multiaddrs to listen on, `/ip4/0.0.0.0/tcp/0` if none are given.
+    listen: Vec<String>,
+    /// How long connection setup (dial, handshake and multiplexer negotiation) may take.
+    handshake_timeout: Duration,
+    /// Cap on established connections in each direction, unlimited if unset.
+    max_connections: Option<u32>,
+    /// Cap on connections still being negotiated in each direction, unlimited if unset.
+    max_pending_connections: Option<u32>,
+    /// Reload the songs file when another program changes it.
+    watch: bool,
+    /// Exit once nothing has happened for this long. Off unless set.
+    idle_timeout: Option<Duration>,
+    /// Commands to run from a file before reading stdin.
+    script: Option<PathBuf>,
+    /// Keep running a script after a command that is unknown or fails.
+    continue_on_error: bool,
+    /// Keep the keypair, and so the peer id, in the data directory across restarts.
+    persist_identity: bool,
+    /// Library in use, every profile but `default` lives in `profiles/<name>` under `--data-dir`.
+    profile: String,
+    /// Directory holding the songs, settings and identity files of the profile.
+    data_dir: PathBuf,
+    /// Use an unencrypted, unauthenticated transport. Only meant for loopback testing.
+    insecure: bool,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let profile = profile_setting();
+        Config {
+            discovery_retries: parse_setting(
+                "--discovery-retries",
+                "SPOTIFY_BLOG_DISCOVERY_RETRIES",
+                3,
+            ),
+            discovery_interval: Duration::from_secs(parse_setting(
+                "--discovery-interval",
+                "SPOTIFY_BLOG_DISCOVERY_INTERVAL",
+                2,
+            )),
+            auto_announce: flag_setting("--auto-announce", "SPOTIFY_BLOG_AUTO_ANNOUNCE"),
+            compress: flag_setting("--compress", "SPOTIFY_BLOG_COMPRESS"),
+            announce_unpublish: flag_setting(
+                "--announce-unpublish",
+                "SPOTIFY_BLOG_ANNOUNCE_UNPUBLISH",
+            ),
+            auto_refresh: flag_setting("--auto-refresh", "SPOTIFY_BLOG_AUTO_REFRESH"),
+            response_timeout: Duration::from_secs(parse_setting(
+                "--response-timeout",
+                "SPOTIFY_BLOG_RESPONSE_TIMEOUT",
+                3,
+            )),
+            max_queries: parse_setting("--max-queries", "SPOTIFY_BLOG_MAX_QUERIES", 1),
+            max_peers_query: optional_setting("--max-peers-query", "SPOTIFY_BLOG_MAX_PEERS_QUERY"),
+            default_explicit: setting("--default-explicit", "SPOTIFY_BLOG_DEFAULT_EXPLICIT")
+                .and_then(|value| parse_explicit(&value))
+                .unwrap_or(false),
+            stream_responses: flag_setting("--stream-responses", "SPOTIFY_BLOG_STREAM_RESPONSES"),
+            quiet: flag_setting("--quiet", "SPOTIFY_BLOG_QUIET"),
+            inline_covers: flag_setting("--inline-covers", "SPOTIFY_BLOG_INLINE_COVERS"),
+            prompt: setting("--prompt", "SPOTIFY_BLOG_PROMPT")
+                .unwrap_or_else(|| "spotify-blog> ".to_owned()),
+            join_jitter: Duration::from_millis(parse_setting(
+                "--join-jitter-ms",
+                "SPOTIFY_BLOG_JOIN_JITTER_MS",
+                500,
+            )),
+            expire_grace: Duration::from_secs(parse_setting(
+                "--expire-grace",
+                "SPOTIFY_BLOG_EXPIRE_GRACE",
+                30,
+            )),
+            rejoin_cooldown: Duration::from_secs(parse_setting(
+                "--rejoin-cooldown",
+                "SPOTIFY_BLOG_REJOIN_COOLDOWN",
+                10,
+            )),
+            ping_interval: Duration::from_secs(parse_setting(
+                "--ping-interval",
+                "SPOTIFY_BLOG_PING_INTERVAL",
+                15,
+            )),
+            follow_interval: Duration::from_secs(
+                parse_setting::<u64>("--follow-interval", "SPOTIFY_BLOG_FOLLOW_INTERVAL", 300)
+                    .max(30),
+            ),
+            topics: setting("--topic-list", "SPOTIFY_BLOG_TOPICS")
+                .map(|list| {
+                    list.split(',')
+                        .map(str::trim)
+                        .filter(|t| !t.is_empty())
+                        .map(str::to_owned)
+                        .collect::<Vec<_>>()
+                })
+                .filter(|topics| !topics.is_empty())
+                .unwrap_or_else(|| vec!["songs".to_owned()]),
+            listen: repeated_setting("--listen", "SPOTIFY_BLOG_LISTEN"),
+            handshake_timeout: Duration::from_secs(parse_setting(
+                "--handshake-timeout",
+                "SPOTIFY_BLOG_HANDSHAKE_TIMEOUT",
+                10,
+            )),
+            max_connections: optional_setting("--max-connections", "SPOTIFY_BLOG_MAX_CONNECTIONS"),
+            max_pending_connections: optional_setting(
+                "--max-pending-connections",
+                "SPOTIFY_BLOG_MAX_PENDING_CONNECTIONS",
+            ),
+            watch: flag_setting("--watch", "SPOTIFY_BLOG_WATCH"),
+            idle_timeout: optional_setting("--idle-timeout", "SPOTIFY_BLOG_IDLE_TIMEOUT")
+                .map(Duration::from_secs),
+            script: setting("--script", "SPOTIFY_BLOG_SCRIPT").map(PathBuf::from),
+            continue_on_error: flag_setting(
+                "--continue-on-error",
+                "SPOTIFY_BLOG_CONTINUE_ON_ERROR",
+            ),
+            persist_identity: flag_setting("--persist-identity", "SPOTIFY_BLOG_PERSIST_IDENTITY"),
+            data_dir: profile_dir(
+                setting("--data-dir", "SPOTIFY_BLOG_DATA_DIR")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| PathBuf::from(".")),
+                &profile,
+            ),
+            profile,
+            insecure: matches!(
+                std::env::var("SPOTIFY_BLOG_INSECURE").as_deref(),
+                Ok("1") | Ok("true")
+            ),
+        }
+    }
+}
+
+/// Looks up `--flag value` / `--flag=value` in the arguments, falling back to the env var.
+fn setting(flag: &str, var: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(flag).and_then(|r| r.strip_prefix('=')) {
+            return Some(value.to_owned());
+        }
+    }
+    std::env::var(var).ok()
+}
+
+/// Collects every `--flag value` / `--flag=value`, or the comma separated env var if none are passed.
+fn repeated_setting(flag: &str, var: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            values.extend(args.next());
+        } else if let Some(value) = arg.strip_prefix(flag).and_then(|r| r.strip_prefix('=')) {
+            values.push(value.to_owned());
+        }
+    }
+    if values.is_empty() {
+        if let Ok(list) = std::env::var(var) {
+            values = list
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+    }
+    values
+}
+
+/// A boolean switch is on if the flag is passed or the env var is set to `1`/`true`.
+fn flag_setting(flag: &str, var: &str) -> bool {
+    std::env::args().skip(1).any(|arg| arg == flag)
+        || matches!(std::env::var(var).as_deref(), Ok("1") | Ok("true"))
+}
+
+/// A random delay up to `join_jitter`, so nodes starting together don't join in lockstep.
+fn join_delay() -> Duration {
+    let max = CONFIG.join_jitter.as_millis() as u64;
+    if max == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(0..=max))
+}
+
+fn parse_setting<T: FromStr>(flag: &str, var: &str, default: T) -> T {
+    match setting(flag, var) {
+        Some(value) => value.parse().unwrap_or_else(|_| {
+            error!("invalid value for {}: {}, using default", flag, value);
+            default
+        }),
+        None => default,
+    }
+}
+
+/// The `--profile` name, falling back to `default` if it isn't a plain name.
+fn profile_setting() -> String {
+    let profile =
+        setting("--profile", "SPOTIFY_BLOG_PROFILE").unwrap_or_else(|| "default".to_owned());
+    let valid = !profile.is_empty()
+        && profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        profile
+    } else {
+        error!(
+            "invalid profile name: {}, use letters, digits, - and _; using default",
+            profile
+        );
+        "default".to_owned()
+    }
+}
+
+/// The `default` profile uses the data dir itself, so libraries from before profiles keep working.
+fn profile_dir(data_dir: PathBuf, profile: &str) -> PathBuf {
+    if profile == "default" {
+        data_dir
+    } else {
+        data_dir.join("profiles").join(profile)
+    }
+}
+
+/// Like `parse_setting`, for options that are off unless set.
+fn optional_setting<T: FromStr>(flag: &str, var: &str) -> Option<T> {
+    let value = setting(flag, var)?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            error!("invalid value for {}: {}, ignoring it", flag, value);
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct Song {
     id: usize,
     title: String,
     artist: String,
     lyrics: String,
-    explicit: String,
+    #[serde(
+        serialize_with = "serialize_explicit",
+        deserialize_with = "deserialize_explicit"
+    )]
+    explicit: bool,
     public: bool,
+    /// Unix timestamp of the last local change, missing for songs created before it existed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<u64>,
+    /// Hash of the song's content, written on save and checked on load to catch corruption.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    /// Where a song saved from a peer came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<SongOrigin>,
+    /// Private annotations, never sent to peers.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    notes: String,
+    /// Peer ids a public song is limited to, everyone on the topic if unset or empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shared_with: Option<Vec<String>>,
+    /// Where the song was collected from, like `local`, `spotify` or `bandcamp`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    platform: Option<String>,
+    /// Cover art as a local image path or a URL, only URLs are sent to peers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cover: Option<String>,
+    /// Nickname, or peer id without one, of whoever first added the song. Sent to peers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    added_by: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Song {
+    fn visible_to(&self, peer_id: &str) -> bool {
+        self.public
+            && match &self.shared_with {
+                Some(peers) if !peers.is_empty() => peers.iter().any(|p| p == peer_id),
+                _ => true,
+            }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct SongOrigin {
+    peer: String,
+    id: usize,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum ListMode {
     ALL,
     One(String),
@@ -44,6 +462,9 @@ enum ListMode {
 #[derive(Debug, Serialize, Deserialize)]
 struct ListRequest {
     mode: ListMode,
+    /// Set with `--compress`; peers that predate it ignore the field and answer with plain JSON.
+    #[serde(default)]
+    accept_gzip: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -51,289 +472,5265 @@ struct ListResponse {
     mode: ListMode,
     data: Songs,
     receiver: String,
+    /// Protobuf encoded public key of the author, must match the floodsub source.
+    #[serde(
+        default,
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    public_key: Vec<u8>,
+    #[serde(
+        default,
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    signature: Vec<u8>,
+    /// Whether the requester accepts a gzipped response, never sent.
+    #[serde(skip)]
+    compress: bool,
 }
 
-enum EventType {
-    Response(ListResponse),
-    Input(String),
+/// Bytes as a base64 string, a JSON array of numbers takes about four times the space.
+fn serialize_base64<S: serde::Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::encode(bytes))
 }
 
-#[derive(NetworkBehaviour)]
-struct SongBehaviour {
-    floodsub: Floodsub,
-    mdns: Mdns,
-    #[behaviour(ignore)]
-    response_sender: mpsc::UnboundedSender<ListResponse>,
+fn deserialize_base64<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    base64::decode(&encoded).map_err(serde::de::Error::custom)
 }
 
-impl NetworkBehaviourEventProcess<FloodsubEvent> for SongBehaviour {
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        match event {
-            FloodsubEvent::Message(msg) => {
-                if let Ok(resp) = serde_json::from_slice::<ListResponse>(&msg.data) {
-                    if resp.receiver == PEER_ID.to_string() {
-                        info!("Response from {}:", msg.source);
-                        resp.data.iter().for_each(|r| info!("{:?}", r));
-                    }
-                } else if let Ok(req) = serde_json::from_slice::<ListRequest>(&msg.data) {
-                    match req.mode {
-                        ListMode::ALL => {
-                            info!("Received ALL req: {:?} from {:?}", req, msg.source);
-                            respond_with_public_songs(
-                                self.response_sender.clone(),
-                                msg.source.to_string(),
-                            );
-                        }
-                        ListMode::One(ref peer_id) => {
-                            if peer_id == &PEER_ID.to_string() {
-                                info!("Received req: {:?} from {:?}", req, msg.source);
-                                respond_with_public_songs(
-                                    self.response_sender.clone(),
-                                    msg.source.to_string(),
-                                );
-                            }
-                        }
-                    }
-                }
-            }
-            _ => (),
+impl ListResponse {
+    /// Relaying peers could alter a response, so its content is signed by the author.
+    fn signing_payload(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.mode, &self.data, &self.receiver)).expect("can jsonify response")
+    }
+
+    fn sign(&mut self) {
+        self.sign_with(&KEYS);
+    }
+
+    fn sign_with(&mut self, keys: &identity::Keypair) {
+        self.public_key = keys.public().into_protobuf_encoding();
+        match keys.sign(&self.signing_payload()) {
+            Ok(signature) => self.signature = signature,
+            Err(e) => error!("error signing response: {}", e),
         }
     }
-}
 
-fn respond_with_public_songs(sender: mpsc::UnboundedSender<ListResponse>, receiver: String) {
-    tokio::spawn(async move {
-        match read_local_songs().await {
-            Ok(songs) => {
-                let resp = ListResponse {
-                    mode: ListMode::ALL,
-                    receiver,
-                    data: songs.into_iter().filter(|r| r.public).collect(),
-                };
-                if let Err(e) = sender.send(resp) {
-                    error!("error sending response via channel, {}", e);
-                }
+    fn verify(&self, source: &PeerId) -> bool {
+        match identity::PublicKey::from_protobuf_encoding(&self.public_key) {
+            Ok(key) => {
+                PeerId::from(key.clone()) == *source
+                    && key.verify(&self.signing_payload(), &self.signature)
             }
-            Err(e) => error!("error fetching local songs to answer ALL request, {}", e),
+            Err(_) => false,
         }
-    });
+    }
 }
 
-impl NetworkBehaviourEventProcess<MdnsEvent> for SongBehaviour {
-    fn inject_event(&mut self, event: MdnsEvent) {
-        match event {
-            MdnsEvent::Discovered(discovered_list) => {
-                for (peer, _addr) in discovered_list {
-                    self.floodsub.add_node_to_partial_view(peer);
-                }
-            }
-            MdnsEvent::Expired(expired_list) => {
-                for (peer, _addr) in expired_list {
-                    if !self.mdns.has_node(&peer) {
-                        self.floodsub.remove_node_from_partial_view(&peer);
-                    }
-                }
-            }
+#[derive(Debug, Clone, Copy)]
+enum SortKey {
+    Title,
+    Artist,
+}
+
+impl SortKey {
+    fn compare(self, a: &Song, b: &Song) -> Ordering {
+        let title = |s: &Song| s.title.trim().to_lowercase();
+        let artist = |s: &Song| s.artist.trim().to_lowercase();
+        match self {
+            SortKey::Title => title(a).cmp(&title(b)).then(artist(a).cmp(&artist(b))),
+            SortKey::Artist => artist(a).cmp(&artist(b)).then(title(a).cmp(&title(b))),
         }
     }
 }
 
-async fn create_new_song(title: &str, artist: &str, lyrics: &str, explicit: &str) -> Result<()> {
-    let mut local_songs = read_local_songs().await?;
-    let new_id = match local_songs.iter().max_by_key(|r| r.id) {
-        Some(v) => v.id + 1,
-        None => 0,
-    };
-    local_songs.push(Song {
-        id: new_id,
-        title: title.to_owned(),
-        artist: artist.to_owned(),
-        lyrics: lyrics.to_owned(),
-        explicit: explicit.to_owned(),
-        public: false,
-    });
-    write_local_songs(&local_songs).await?;
-
-    info!("Created song:");
-    info!("Title: {}", title);
-    info!("Artist: {}", artist);
-    info!("Lyrics:: {}", lyrics);
-    info!("Explicit:: {}", explicit);
+impl FromStr for SortKey {
+    type Err = String;
 
-    Ok(())
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "title" => Ok(SortKey::Title),
+            "artist" => Ok(SortKey::Artist),
+            _ => Err(format!(
+                "unknown sort key: {} (expected title or artist)",
+                s
+            )),
+        }
+    }
 }
 
-async fn publish_song(id: usize) -> Result<()> {
-    let mut local_songs = read_local_songs().await?;
-    local_songs
-        .iter_mut()
-        .filter(|r| r.id == id)
-        .for_each(|r| r.public = true);
-    write_local_songs(&local_songs).await?;
-    Ok(())
+/// What to do with the collected responses once a query completes.
+#[derive(Debug, Clone, Copy)]
+enum QueryKind {
+    List(Option<SortKey>),
+    Clone,
+    Seed,
+    Compare,
+    /// `verify peer`, remembering whether a Noise-authenticated connection existed at the start.
+    Verify(bool),
+    /// A background re-query of a peer from `follow`.
+    Follow,
 }
 
-async fn read_local_songs() -> Result<Songs> {
-    let content = fs::read(STORAGE_FILE_PATH).await?;
-    let result = serde_json::from_slice(&content)?;
-    Ok(result)
+impl fmt::Display for QueryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryKind::List(_) => write!(f, "list songs"),
+            QueryKind::Clone => write!(f, "clone peer"),
+            QueryKind::Seed => write!(f, "seed"),
+            QueryKind::Compare => write!(f, "compare all"),
+            QueryKind::Verify(_) => write!(f, "verify peer"),
+            QueryKind::Follow => write!(f, "follow"),
+        }
+    }
 }
 
-async fn write_local_songs(songs: &Songs) -> Result<()> {
-    let json = serde_json::to_string(&songs)?;
-    fs::write(STORAGE_FILE_PATH, &json).await?;
-    Ok(())
+/// A `list songs` request whose responses are collected until the response timeout.
+struct PendingQuery {
+    mode: ListMode,
+    kind: QueryKind,
+    started: Instant,
+    responses: Vec<(String, Songs)>,
+    /// Responses claiming an accepted source whose signature didn't match it.
+    rejected: usize,
+    /// Responses dropped because `--max-peers-query` peers had already answered.
+    ignored: usize,
 }
 
-#[tokio::main]
-async fn main() {
-    pretty_env_logger::init();
+impl PendingQuery {
+    fn accepts(&self, source: &str) -> bool {
+        match &self.mode {
+            ListMode::ALL => true,
+            ListMode::One(peer_id) => peer_id == source,
+        }
+    }
 
-    info!("Peer Id: {}", PEER_ID.clone());
-    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
+    /// Whether a query to all peers already has responses from `--max-peers-query` other peers.
+    fn is_full(&self, source: &str) -> bool {
+        let max = match (&self.mode, CONFIG.max_peers_query) {
+            (ListMode::ALL, Some(max)) => max,
+            _ => return false,
+        };
+        self.responses.len() >= max && !self.responses.iter().any(|(s, _)| s == source)
+    }
 
-    let auth_keys = Keypair::<X25519Spec>::new()
-        .into_authentic(&KEYS)
-        .expect("can create auth keys");
+    fn is_complete(&self) -> bool {
+        let answered = matches!(self.mode, ListMode::One(_)) && !self.responses.is_empty();
+        answered || self.started.elapsed() >= CONFIG.response_timeout
+    }
+}
 
-    let transp = TokioTcpConfig::new()
-        .upgrade(upgrade::Version::V1)
-        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated()) // XX Handshake pattern, IX exists as well and IK - only XX currently provides interop with other libp2p impls
-        .multiplex(mplex::MplexConfig::new())
-        .boxed();
+/// A peer from `follow`, asked for its songs every `--follow-interval`.
+struct FollowedPeer {
+    /// Content checksums of the songs in its last answer, `None` until it first answers.
+    seen: Option<HashSet<String>>,
+    next_query: Instant,
+}
 
-    let mut behaviour = SongBehaviour {
-        floodsub: Floodsub::new(PEER_ID.clone()),
-        mdns: Mdns::new(Default::default())
-            .await
-            .expect("can create mdns"),
-        response_sender,
-    };
+#[derive(Debug, Serialize, Deserialize)]
+/// A command waiting for the user to answer y/n on the next input line.
+enum PendingAction {
+    ClonePeer(String),
+    SeedPeer(String),
+    ResetIdentity,
+    /// Song ids from `merge duplicates`, the first one is kept.
+    MergeDuplicates(Vec<usize>),
+}
 
-    behaviour.floodsub.subscribe(TOPIC.clone());
+#[derive(Debug, Serialize, Deserialize)]
+struct LibraryUpdated {
+    source: String,
+    #[serde(default)]
+    nick: Option<String>,
+}
 
-    let mut swarm = SwarmBuilder::new(transp, behaviour, PEER_ID.clone())
-        .executor(Box::new(|fut| {
-            tokio::spawn(fut);
-        }))
-        .build();
+/// Sent when a song stops being public, so peers evict it from their response cache.
+#[derive(Debug, Serialize, Deserialize)]
+struct Unpublish {
+    source: String,
+    id: usize,
+}
 
-    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+/// Broadcast by `census`, every peer answers with a `CensusReply`.
+#[derive(Debug, Serialize, Deserialize)]
+struct CensusRequest {
+    requester: String,
+}
 
-    Swarm::listen_on(
-        &mut swarm,
-        "/ip4/0.0.0.0/tcp/0"
-            .parse()
-            .expect("can get a local socket"),
-    )
-    .expect("swarm can be started");
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CensusReply {
+    receiver: String,
+    #[serde(default)]
+    nick: Option<String>,
+    version: String,
+    /// Songs the requester would get from `list songs <peer id>`.
+    public_songs: usize,
+}
 
-    loop {
-        let evt = {
-            tokio::select! {
-                line = stdin.next_line() => Some(EventType::Input(line.expect("can get line").expect("can read line from stdin"))),
-                response = response_rcv.recv() => Some(EventType::Response(response.expect("response exists"))),
-                event = swarm.select_next_some() => {
-                    info!("Unhandled Swarm Event: {:?}", event);
-                    None
-                },
-            }
-        };
+/// A floodsub message, as told apart on receipt.
+enum Message {
+    ListResponse(ListResponse),
+    ListRequest(ListRequest),
+    Unpublish(Unpublish),
+    CensusRequest(CensusRequest),
+    CensusReply(CensusReply),
+    LibraryUpdated(LibraryUpdated),
+}
 
-        if let Some(event) = evt {
-            match event {
-                EventType::Response(resp) => {
-                    let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm
-                        .behaviour_mut()
-                        .floodsub
-                        .publish(TOPIC.clone(), json.as_bytes());
-                }
-                EventType::Input(line) => match line.as_str() {
-                    "list peers" => handle_list_peers(&mut swarm).await,
-                    cmd if cmd.starts_with("list songs") => handle_list_songs(cmd, &mut swarm).await,
-                    cmd if cmd.starts_with("create song") => handle_create_song(cmd).await,
-                    cmd if cmd.starts_with("publish song") => handle_publish_song(cmd).await,
-                    _ => error!("unknown command"),
-                },
-            }
+impl Message {
+    fn kind(&self) -> &'static str {
+        match self {
+            Message::ListResponse(_) => "ListResponse",
+            Message::ListRequest(_) => "ListRequest",
+            Message::Unpublish(_) => "Unpublish",
+            Message::CensusRequest(_) => "CensusRequest",
+            Message::CensusReply(_) => "CensusReply",
+            Message::LibraryUpdated(_) => "LibraryUpdated",
         }
     }
 }
 
-async fn handle_list_peers(swarm: &mut Swarm<SongBehaviour>) {
-    info!("Discovered Peers:");
-    let nodes = swarm.behaviour().mdns.discovered_nodes();
-    let mut unique_peers = HashSet::new();
-    for peer in nodes {
-        unique_peers.insert(peer);
+/// Parses a decompressed payload as the first message type that accepts it, `None` if none does.
+fn parse_message(data: &[u8]) -> Option<Message> {
+    if let Ok(resp) = serde_json::from_slice(data) {
+        Some(Message::ListResponse(resp))
+    } else if let Ok(req) = serde_json::from_slice(data) {
+        Some(Message::ListRequest(req))
+    } else if let Ok(unpublish) = serde_json::from_slice(data) {
+        // checked before `LibraryUpdated`, which would also accept this message
+        Some(Message::Unpublish(unpublish))
+    } else if let Ok(req) = serde_json::from_slice(data) {
+        Some(Message::CensusRequest(req))
+    } else if let Ok(reply) = serde_json::from_slice(data) {
+        Some(Message::CensusReply(reply))
+    } else {
+        serde_json::from_slice(data)
+            .ok()
+            .map(Message::LibraryUpdated)
     }
-    unique_peers.iter().for_each(|p| info!("{}", p));
 }
 
-async fn handle_list_songs(cmd: &str, swarm: &mut Swarm<SongBehaviour>) {
-    let rest = cmd.strip_prefix("list songs ");
-    match rest {
-        Some("all") => {
-            let req = ListRequest {
-                mode: ListMode::ALL,
-            };
-            let json = serde_json::to_string(&req).expect("can jsonify request");
-            swarm
-                .behaviour_mut()
-                .floodsub
-                .publish(TOPIC.clone(), json.as_bytes());
-        }
-        Some(songs_peer_id) => {
-            let req = ListRequest {
-                mode: ListMode::One(songs_peer_id.to_owned()),
-            };
-            let json = serde_json::to_string(&req).expect("can jsonify request");
-            swarm
-                .behaviour_mut()
-                .floodsub
-                .publish(TOPIC.clone(), json.as_bytes());
-        }
-        None => {
-            match read_local_songs().await {
-                Ok(v) => {
-                    info!("Local Songs ({})", v.len());
-                    v.iter().for_each(|r| info!("{:?}", r));
-                }
-                Err(e) => error!("error fetching local songs: {}", e),
-            };
+/// A `census` collecting replies until the response timeout.
+struct Census {
+    started: Instant,
+    replies: Vec<(String, CensusReply)>,
+}
+
+#[derive(Debug, Serialize)]
+struct LibraryStats {
+    total: usize,
+    public: usize,
+    private: usize,
+    explicit: usize,
+    /// Share of songs marked explicit, 0 to 1.
+    explicit_ratio: f64,
+    /// Mean lyrics length in characters.
+    average_lyrics_chars: f64,
+    shortest_title: Option<String>,
+    longest_title: Option<String>,
+    missing_lyrics: usize,
+    top_artists: Vec<(String, usize)>,
+}
+
+/// One entry of an `export peers` file.
+#[derive(Debug, Serialize, Deserialize)]
+struct PeerRecord {
+    peer_id: String,
+    addresses: Vec<String>,
+}
+
+/// A discovered peer as printed by `list peers json`.
+#[derive(Debug, Serialize)]
+struct PeerStatus {
+    peer_id: String,
+    nickname: Option<String>,
+    addresses: Vec<String>,
+    /// Unix seconds of the last discovery or message from the peer.
+    last_seen: Option<u64>,
+    connected: bool,
+}
+
+/// Node state shown by `whoami`, in both the human and the JSON form.
+#[derive(Debug, Serialize)]
+struct NodeStatus {
+    peer_id: String,
+    nickname: Option<String>,
+    topics: Vec<String>,
+    profile: String,
+    storage_path: String,
+    listen_addresses: Vec<String>,
+    songs: usize,
+    public_songs: usize,
+    auto_respond: bool,
+    catalog_only: bool,
+}
+
+/// Network diagnostics shown by `netstat`.
+#[derive(Debug, Serialize)]
+struct NetStatus {
+    listen_addresses: Vec<String>,
+    connected_peers: usize,
+    topics: Vec<String>,
+    partial_view: usize,
+    mdns_discovered: usize,
+    mdns_query_interval_secs: u64,
+    transport: &'static str,
+}
+
+/// Local preferences persisted next to the songs file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Settings {
+    #[serde(default)]
+    nick: Option<String>,
+    /// Explicit value used when `create song` leaves it out, overrides `--default-explicit`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_explicit: Option<bool>,
+    /// Song listing layout, one line per song unless set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display: Option<DisplayMode>,
+    /// Peer ids or multiaddrs dialed at every start, managed with `pin` and `unpin`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DisplayMode {
+    Compact,
+    Verbose,
+}
+
+impl FromStr for DisplayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "compact" => Ok(DisplayMode::Compact),
+            "verbose" => Ok(DisplayMode::Verbose),
+            other => Err(format!(
+                "unknown display mode: {}, expected compact|verbose",
+                other
+            )),
         }
-    };
+    }
 }
 
-async fn handle_create_song(cmd: &str) {
-    if let Some(rest) = cmd.strip_prefix("create song") {
-        let elements: Vec<&str> = rest.split("|").collect();
-        if elements.len() < 3 {
-            info!("too few arguments - Format: title|artist|lyrics|explicit");
-        } else {
-            let title = elements.get(0).expect("title is there");
-            let artist = elements.get(1).expect("artist is there");
-            let lyrics = elements.get(2).expect("lyrics are there");
-            let explicit = elements.get(3).expect("explicit is there");
-            if let Err(e) = create_new_song(title, artist, lyrics, explicit).await {
-                error!("error creating song: {}", e);
-            };
+enum EventType {
+    Response(ListResponse),
+    CensusReply(CensusReply),
+    Input(String),
+    /// The `edit all` editor exited and its changes were applied or dropped.
+    EditDone,
+    DiscoveryTick,
+    Tick,
+    Quit,
+}
+
+/// Polls the songs file for `--watch`, a change is reloaded once the file stopped changing for a tick.
+struct LibraryWatch {
+    modified: Option<SystemTime>,
+    pending: bool,
+}
+
+impl LibraryWatch {
+    fn new() -> Self {
+        LibraryWatch {
+            modified: library_modified(),
+            pending: false,
+        }
+    }
+
+    /// Whether the file changed and has been stable since the last tick.
+    fn poll(&mut self) -> bool {
+        let modified = library_modified();
+        if modified != self.modified {
+            self.modified = modified;
+            self.pending = true;
+            return false;
         }
+        std::mem::take(&mut self.pending)
     }
 }
 
-async fn handle_publish_song(cmd: &str) {
-    if let Some(rest) = cmd.strip_prefix("publish song") {
-        match rest.trim().parse::<usize>() {
-            Ok(id) => {
-                if let Err(e) = publish_song(id).await {
-                    info!("error publishing song with id {}, {}", id, e)
-                } else {
-                    info!("Published Song with id: {}", id);
-                }
-            }
-            Err(e) => error!("invalid id: {}, {}", rest.trim(), e),
-        };
+fn library_modified() -> Option<SystemTime> {
+    std::fs::metadata(&*STORAGE_FILE_PATH)
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+async fn reload_library() {
+    let content = fs::read_to_string(&*STORAGE_FILE_PATH).await.ok();
+    if content.is_some() && content == *LAST_WRITTEN.lock().expect("last written lock") {
+        return;
+    }
+    match read_local_songs().await {
+        Ok(songs) => info!("Library reloaded from disk ({} songs).", songs.len()),
+        Err(e) => error!("error reloading library: {}", e),
     }
-}
\ No newline at end of file
+}
+
+/// How many entries `recent` remembers.
+const RECENT_LIMIT: usize = 50;
+
+/// Counters for the summary printed at shutdown.
+#[derive(Default)]
+struct SessionStats {
+    requests_answered: usize,
+    songs_served: usize,
+    peers_seen: HashSet<PeerId>,
+}
+
+#[derive(NetworkBehaviour)]
+struct SongBehaviour {
+    floodsub: Floodsub,
+    mdns: Mdns,
+    ping: Ping,
+    #[behaviour(ignore)]
+    response_sender: mpsc::UnboundedSender<ListResponse>,
+    #[behaviour(ignore)]
+    census_sender: mpsc::UnboundedSender<CensusReply>,
+    #[behaviour(ignore)]
+    edit_sender: mpsc::UnboundedSender<()>,
+    /// Whether `edit all` has the terminal, stdin isn't read until it's done.
+    #[behaviour(ignore)]
+    editing: bool,
+    #[behaviour(ignore)]
+    queries: Vec<PendingQuery>,
+    #[behaviour(ignore)]
+    nicknames: HashMap<String, String>,
+    /// Discovered peers waiting for their join delay before entering the floodsub partial view.
+    #[behaviour(ignore)]
+    pending_peers: Vec<(PeerId, Instant)>,
+    #[behaviour(ignore)]
+    confirmation: Option<PendingAction>,
+    /// Latest ping round trip time per peer, or why the last ping failed.
+    #[behaviour(ignore)]
+    latencies: HashMap<PeerId, std::result::Result<Duration, String>>,
+    /// When the last command, message or discovery happened, for `--idle-timeout`.
+    #[behaviour(ignore)]
+    last_activity: Instant,
+    #[behaviour(ignore)]
+    session: SessionStats,
+    /// Whether `ListMode::ALL` requests are answered, toggled with `respond auto`.
+    #[behaviour(ignore)]
+    auto_respond: bool,
+    /// Whether `ListMode::ALL` responses leave out lyrics, toggled with `catalog only`.
+    #[behaviour(ignore)]
+    catalog_only: bool,
+    /// Unix seconds of the last discovery or message per peer.
+    #[behaviour(ignore)]
+    last_seen: HashMap<PeerId, u64>,
+    #[behaviour(ignore)]
+    library_watch: LibraryWatch,
+    /// Songs opened with `show song` this session, oldest first.
+    #[behaviour(ignore)]
+    recent: Vec<(usize, String, Instant)>,
+    /// Mirror of the floodsub partial view, which floodsub doesn't expose.
+    #[behaviour(ignore)]
+    partial_view: HashSet<PeerId>,
+    /// Partial view members expired by mdns, with when they are removed unless rediscovered.
+    #[behaviour(ignore)]
+    expiring: HashMap<PeerId, Instant>,
+    /// Peers from `pin`, kept in the partial view even when mdns expires them.
+    #[behaviour(ignore)]
+    pinned_peers: HashSet<PeerId>,
+    /// When peers were last removed from the partial view, for `--rejoin-cooldown`.
+    #[behaviour(ignore)]
+    removed_peers: HashMap<PeerId, Instant>,
+    /// Floodsub topics joined, every message is published to all of them. Starts as `--topic-list`.
+    #[behaviour(ignore)]
+    topics: Vec<Topic>,
+    /// The songs each peer last sent us, keyed by peer id. Cleared by a new query, `Unpublish`
+    /// messages evict from it.
+    #[behaviour(ignore)]
+    received: HashMap<String, Songs>,
+    /// Peers from `follow`, keyed by peer id.
+    #[behaviour(ignore)]
+    followed: HashMap<String, FollowedPeer>,
+    #[behaviour(ignore)]
+    census: Option<Census>,
+}
+
+impl SongBehaviour {
+    /// Publishes once on all topics, so peers sharing several topics see it once.
+    fn publish(&mut self, data: impl Into<Vec<u8>>) {
+        self.floodsub
+            .publish_many(self.topics.iter().cloned(), data);
+    }
+
+    fn topic_names(&self) -> Vec<String> {
+        self.topics.iter().map(|t| t.id().to_owned()).collect()
+    }
+
+    fn record_shown(&mut self, song: &Song) {
+        if self.recent.len() == RECENT_LIMIT {
+            self.recent.remove(0);
+        }
+        let label = format!("{} - {}", song.title.trim(), song.artist.trim());
+        self.recent.push((song.id, label, Instant::now()));
+    }
+
+    fn on_tick(&mut self) {
+        self.remove_expired_peers();
+        self.add_pending_peers();
+        self.finish_queries();
+        self.query_followed();
+        self.finish_census();
+    }
+
+    fn finish_census(&mut self) {
+        match &self.census {
+            Some(census) if census.started.elapsed() >= CONFIG.response_timeout => {}
+            _ => return,
+        }
+        let mut replies = self.census.take().map(|c| c.replies).unwrap_or_default();
+        if replies.is_empty() {
+            info!("Census: no peers answered");
+        } else {
+            replies.sort_by(|a, b| a.1.nick.cmp(&b.1.nick).then_with(|| a.0.cmp(&b.0)));
+            info!("Census: {} peer(s) answered", replies.len());
+            info!(
+                "  {:<52} {:<16} {:<8} {}",
+                "peer", "nickname", "version", "public songs"
+            );
+            for (source, reply) in &replies {
+                info!(
+                    "  {:<52} {:<16} {:<8} {}",
+                    source,
+                    reply.nick.as_deref().unwrap_or("-"),
+                    reply.version,
+                    reply.public_songs
+                );
+            }
+        }
+        show_prompt();
+    }
+
+    /// Starts the re-query of one due followed peer, only while no other query runs so
+    /// follows neither delay the user's queries nor flood the topic.
+    fn query_followed(&mut self) {
+        if !self.queries.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let due = self
+            .followed
+            .iter_mut()
+            .filter(|(_, followed)| followed.next_query <= now)
+            .min_by_key(|(_, followed)| followed.next_query);
+        if let Some((peer_id, followed)) = due {
+            followed.next_query = now + CONFIG.follow_interval;
+            let mode = ListMode::One(peer_id.clone());
+            // follow queries aren't limited by --max-queries, starting one can't fail
+            let _ = self.start_query(mode, QueryKind::Follow);
+        }
+    }
+
+    fn start_query(&mut self, mode: ListMode, kind: QueryKind) -> Result<()> {
+        let follow = matches!(kind, QueryKind::Follow);
+        let running = self
+            .queries
+            .iter()
+            .filter(|q| !matches!(q.kind, QueryKind::Follow))
+            .count();
+        if !follow && running >= CONFIG.max_queries {
+            return Err("A query is already in progress.".into());
+        }
+        let req = ListRequest {
+            mode: mode.clone(),
+            accept_gzip: CONFIG.compress,
+        };
+        let json = serde_json::to_string(&req).expect("can jsonify request");
+        self.publish(json.as_bytes());
+        if !follow {
+            self.received.clear();
+        }
+        self.queries.push(PendingQuery {
+            mode,
+            kind,
+            started: Instant::now(),
+            responses: Vec::new(),
+            rejected: 0,
+            ignored: 0,
+        });
+        if follow {
+            return Ok(());
+        }
+        if std::io::stderr().is_terminal() {
+            self.show_query_progress();
+        } else {
+            info!(
+                "Waiting {}s for responses...",
+                CONFIG.response_timeout.as_secs()
+            );
+        }
+        Ok(())
+    }
+
+    /// Reports the songs a followed peer added or changed since its previous answer.
+    /// Returns whether anything was printed.
+    fn check_followed(&mut self, query: PendingQuery) -> bool {
+        let (source, songs) = match query.responses.into_iter().next() {
+            Some(response) => response,
+            None => return false,
+        };
+        let followed = match self.followed.get_mut(&source) {
+            Some(followed) => followed,
+            None => return false,
+        };
+        let checksums: HashSet<String> = songs.iter().map(content_checksum).collect();
+        let name = match self.nicknames.get(&source) {
+            Some(nick) => format!("{} ({})", source, nick),
+            None => source.clone(),
+        };
+        let printed = match &followed.seen {
+            None => {
+                info!("Following {}, {} public song(s)", name, songs.len());
+                true
+            }
+            Some(seen) => {
+                let new: Vec<&Song> = songs
+                    .iter()
+                    .filter(|s| !seen.contains(&content_checksum(s)))
+                    .collect();
+                if !new.is_empty() {
+                    info!("{} new song(s) from {}:", new.len(), name);
+                    new.iter().for_each(|s| print_song(s, None));
+                }
+                !new.is_empty()
+            }
+        };
+        followed.seen = Some(checksums);
+        printed
+    }
+
+    fn add_pending_peers(&mut self) {
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = self
+            .pending_peers
+            .drain(..)
+            .partition(|(_, due_at)| *due_at <= now);
+        self.pending_peers = waiting;
+        for (peer, _) in due {
+            self.floodsub.add_node_to_partial_view(peer);
+            self.partial_view.insert(peer);
+        }
+    }
+
+    fn remove_expired_peers(&mut self) {
+        let now = Instant::now();
+        let due: Vec<PeerId> = self
+            .expiring
+            .iter()
+            .filter(|(_, remove_at)| **remove_at <= now)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in due {
+            self.expiring.remove(&peer);
+            self.floodsub.remove_node_from_partial_view(&peer);
+            self.partial_view.remove(&peer);
+            self.removed_peers.insert(peer, now);
+        }
+        self.removed_peers
+            .retain(|_, removed_at| now.duration_since(*removed_at) < CONFIG.rejoin_cooldown);
+    }
+
+    fn finish_queries(&mut self) {
+        let (done, pending): (Vec<_>, Vec<_>) =
+            self.queries.drain(..).partition(PendingQuery::is_complete);
+        self.queries = pending;
+        let (follows, done): (Vec<_>, Vec<_>) = done
+            .into_iter()
+            .partition(|q| matches!(q.kind, QueryKind::Follow));
+        let mut printed = !done.is_empty();
+        if printed && std::io::stderr().is_terminal() {
+            eprint!("\r\x1b[K");
+        }
+        done.into_iter().for_each(complete_query);
+        for query in follows {
+            printed |= self.check_followed(query);
+        }
+        if printed {
+            show_prompt();
+        }
+        self.show_query_progress();
+    }
+
+    /// Keeps a single "Waiting for responses" line updated on the terminal while queries run.
+    fn show_query_progress(&self) {
+        if !std::io::stderr().is_terminal() {
+            return;
+        }
+        let remaining = self
+            .queries
+            .iter()
+            .filter(|q| !matches!(q.kind, QueryKind::Follow))
+            .map(|q| CONFIG.response_timeout.saturating_sub(q.started.elapsed()))
+            .max();
+        if let Some(remaining) = remaining {
+            eprint!(
+                "\rWaiting for responses ({}s)...\x1b[K",
+                remaining.as_secs_f32().ceil()
+            );
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<FloodsubEvent> for SongBehaviour {
+    fn inject_event(&mut self, event: FloodsubEvent) {
+        if let FloodsubEvent::Message(msg) = event {
+            self.last_activity = Instant::now();
+            self.session.peers_seen.insert(msg.source);
+            self.last_seen.insert(msg.source, now_secs());
+            let data = match inflate_message(&msg.data) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!(
+                        "Dropping message from {} that doesn't decompress: {}",
+                        msg.source, e
+                    );
+                    return;
+                }
+            };
+            match parse_message(&data) {
+                Some(Message::ListResponse(resp)) => {
+                    if resp.receiver == PEER_ID.to_string() && !resp.verify(&msg.source) {
+                        warn!("Dropping response from {} with a bad signature", msg.source);
+                        let source = msg.source.to_string();
+                        for query in self.queries.iter_mut().filter(|q| q.accepts(&source)) {
+                            query.rejected += 1;
+                        }
+                    } else if resp.receiver == PEER_ID.to_string() {
+                        let source = msg.source.to_string();
+                        let mut collected = false;
+                        let mut capped = false;
+                        for query in self.queries.iter_mut().filter(|q| q.accepts(&source)) {
+                            if query.is_full(&source) {
+                                query.ignored += 1;
+                                capped = true;
+                                continue;
+                            }
+                            query.responses.push((source.clone(), resp.data.clone()));
+                            if CONFIG.stream_responses && matches!(query.kind, QueryKind::List(_)) {
+                                print_streamed_response(query.responses.len(), &source, &resp.data);
+                            }
+                            collected = true;
+                        }
+                        if collected || !capped {
+                            self.received.insert(source, resp.data.clone());
+                        }
+                        if !collected && !capped {
+                            info!("Response from {}:", msg.source);
+                            resp.data.iter().for_each(|r| print_song(r, None));
+                        }
+                    }
+                }
+                Some(Message::ListRequest(req)) => match req.mode {
+                    ListMode::ALL if !self.auto_respond => {
+                        info!(
+                            "Ignoring ALL req from {:?}, auto respond is off",
+                            msg.source
+                        );
+                    }
+                    ListMode::ALL => {
+                        info!("Received ALL req: {:?} from {:?}", req, msg.source);
+                        respond_with_public_songs(
+                            self.response_sender.clone(),
+                            msg.source.to_string(),
+                            req.accept_gzip,
+                            self.catalog_only,
+                        );
+                    }
+                    ListMode::One(ref peer_id) => {
+                        if peer_id == &PEER_ID.to_string() {
+                            info!("Received req: {:?} from {:?}", req, msg.source);
+                            respond_with_public_songs(
+                                self.response_sender.clone(),
+                                msg.source.to_string(),
+                                req.accept_gzip,
+                                false,
+                            );
+                        }
+                    }
+                },
+                Some(Message::Unpublish(unpublish)) => {
+                    if unpublish.source != msg.source.to_string() {
+                        warn!(
+                            "Ignoring unpublish for {} sent by {}",
+                            unpublish.source, msg.source
+                        );
+                    } else {
+                        let cached = self.received.get_mut(&unpublish.source).into_iter();
+                        let pending = self
+                            .queries
+                            .iter_mut()
+                            .flat_map(|q| q.responses.iter_mut())
+                            .filter(|(source, _)| *source == unpublish.source)
+                            .map(|(_, songs)| songs);
+                        let mut dropped = false;
+                        for songs in cached.chain(pending) {
+                            let before = songs.len();
+                            songs.retain(|s| s.id != unpublish.id);
+                            dropped |= songs.len() < before;
+                        }
+                        if dropped {
+                            info!(
+                                "Peer {} unpublished song {}, dropped the cached copy",
+                                unpublish.source, unpublish.id
+                            );
+                        }
+                    }
+                }
+                Some(Message::CensusRequest(req)) => {
+                    if self.auto_respond {
+                        answer_census(self.census_sender.clone(), req.requester);
+                    } else {
+                        info!("Ignoring census from {}, auto respond is off", msg.source);
+                    }
+                }
+                Some(Message::CensusReply(reply)) => {
+                    let source = msg.source.to_string();
+                    if let Some(census) = self.census.as_mut() {
+                        if reply.receiver == PEER_ID.to_string()
+                            && !census.replies.iter().any(|(s, _)| s == &source)
+                        {
+                            if let Some(nick) = &reply.nick {
+                                self.nicknames.insert(source.clone(), nick.clone());
+                            }
+                            census.replies.push((source, reply));
+                        }
+                    }
+                }
+                Some(Message::LibraryUpdated(update)) => {
+                    match &update.nick {
+                        Some(nick) => {
+                            info!("Peer {} ({}) updated their library", update.source, nick);
+                            self.nicknames.insert(msg.source.to_string(), nick.clone());
+                        }
+                        None => info!("Peer {} updated their library", update.source),
+                    }
+                    if CONFIG.auto_refresh {
+                        let req = ListRequest {
+                            mode: ListMode::One(update.source),
+                            accept_gzip: CONFIG.compress,
+                        };
+                        let json = serde_json::to_string(&req).expect("can jsonify request");
+                        self.publish(json.as_bytes());
+                    }
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+/// Serializes a response, gzipped when the requester accepts it and it's big enough to gain.
+fn encode_response(resp: &ListResponse) -> Vec<u8> {
+    let json = serde_json::to_vec(resp).expect("can jsonify response");
+    if !resp.compress || json.len() < COMPRESS_MIN_BYTES {
+        return json;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&json)
+        .and_then(|_| encoder.finish())
+        .unwrap_or(json)
+}
+
+/// Unpacks gzipped messages, recognized by the gzip magic bytes JSON can't start with.
+fn inflate_message(data: &[u8]) -> std::io::Result<Cow<'_, [u8]>> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(Cow::Borrowed(data));
+    }
+    let mut inflated = Vec::new();
+    GzDecoder::new(data)
+        .take(MAX_INFLATED_BYTES + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() as u64 > MAX_INFLATED_BYTES {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "message too large",
+        ));
+    }
+    Ok(Cow::Owned(inflated))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Parses durations like `90s`, `15m`, `1h`, `2d` or `1w`.
+fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration: {:?}", s))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("invalid duration unit in {:?}, use s/m/h/d/w", s)),
+    };
+    let secs = amount
+        .checked_mul(unit_secs)
+        .ok_or_else(|| format!("invalid duration: {:?}", s))?;
+    Ok(Duration::from_secs(secs))
+}
+
+fn content_checksum(song: &Song) -> String {
+    checksum_with_explicit(song, if song.explicit { "true" } else { "false" })
+}
+
+/// The checksum with `explicit` spelled as given, songs stored with "yes" or "1" were hashed so.
+fn checksum_with_explicit(song: &Song, explicit: &str) -> String {
+    let mut hasher = Sha256::new();
+    for field in [&song.title, &song.artist, &song.lyrics, explicit] {
+        hasher.update(field.as_bytes());
+        hasher.update([0]);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Songs without a checksum predate it and are not reported.
+fn has_bad_checksum(song: &Song) -> bool {
+    song.checksum
+        .as_ref()
+        .is_some_and(|c| *c != content_checksum(song))
+}
+
+/// Songs are considered the same across peers if title and artist match, ignoring case.
+fn song_key(song: &Song) -> (String, String) {
+    (
+        song.title.trim().to_lowercase(),
+        song.artist.trim().to_lowercase(),
+    )
+}
+
+/// Lowercased with punctuation dropped and whitespace collapsed, so "Hey Jude " matches "hey  jude!".
+fn fuzzy_key(s: &str) -> String {
+    s.to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            row.push(substitution.min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+/// Deliberately strict: explicit and clean versions never match, the artists must match after
+/// `fuzzy_key`, and the titles too or be one edit apart in a title of 8+ characters with the
+/// same digits, so "Part 1" and "Part 2" differ.
+fn near_duplicates(a: &Song, b: &Song) -> bool {
+    if a.explicit != b.explicit {
+        return false;
+    }
+    let artist = fuzzy_key(&a.artist);
+    if artist.is_empty() || artist != fuzzy_key(&b.artist) {
+        return false;
+    }
+    let (ta, tb) = (fuzzy_key(&a.title), fuzzy_key(&b.title));
+    if ta.is_empty() || ta == tb {
+        return !ta.is_empty();
+    }
+    let digits = |s: &str| s.chars().filter(char::is_ascii_digit).collect::<String>();
+    ta.chars().count().min(tb.chars().count()) >= 8
+        && digits(&ta) == digits(&tb)
+        && edit_distance(&ta, &tb) <= 1
+}
+
+/// Groups of two or more near-duplicates, each ordered by id. A song joins the first group with
+/// a near-duplicate of it, chains across groups aren't merged.
+fn duplicate_groups(songs: &[Song]) -> Vec<Vec<&Song>> {
+    let mut sorted: Vec<&Song> = songs.iter().collect();
+    sorted.sort_by_key(|s| s.id);
+    let mut groups: Vec<Vec<&Song>> = Vec::new();
+    for song in sorted {
+        match groups
+            .iter_mut()
+            .find(|g| g.iter().any(|s| near_duplicates(s, song)))
+        {
+            Some(group) => group.push(song),
+            None => groups.push(vec![song]),
+        }
+    }
+    groups.retain(|g| g.len() > 1);
+    groups
+}
+
+fn complete_query(query: PendingQuery) {
+    if query.ignored > 0 {
+        info!(
+            "Ignored {} response(s) after the first {} peers (--max-peers-query)",
+            query.ignored,
+            query.responses.len()
+        );
+    }
+    match query.kind {
+        QueryKind::List(sort) => print_query_results(query, sort),
+        QueryKind::Clone => {
+            tokio::spawn(clone_peer_songs(query, false));
+        }
+        QueryKind::Seed => {
+            tokio::spawn(clone_peer_songs(query, true));
+        }
+        QueryKind::Compare => {
+            tokio::spawn(report_unique_songs(query));
+        }
+        QueryKind::Verify(connected) => report_verification(&query, connected),
+        QueryKind::Follow => unreachable!("handled by finish_queries"),
+    }
+}
+
+/// Compares the floodsub source of a `verify peer` answer with the connection identity.
+fn report_verification(query: &PendingQuery, connected: bool) {
+    let peer_id = match &query.mode {
+        ListMode::One(peer_id) => peer_id,
+        ListMode::ALL => return,
+    };
+    let answered = !query.responses.is_empty();
+    if query.rejected > 0 {
+        warn!(
+            "MISMATCH: {} response(s) claimed to come from {} but weren't signed by its key",
+            query.rejected, peer_id
+        );
+    }
+    match (answered, connected) {
+        (true, true) => info!(
+            "VERIFIED: {} answered with its own signature over a Noise-authenticated connection",
+            peer_id
+        ),
+        (true, false) => info!(
+            "PARTLY VERIFIED: {} signed its answer, but it was relayed, there is no direct connection to it",
+            peer_id
+        ),
+        (false, true) => warn!(
+            "UNVERIFIED: connected to {}, but no correctly signed answer arrived within {}s",
+            peer_id,
+            CONFIG.response_timeout.as_secs()
+        ),
+        (false, false) => warn!(
+            "UNVERIFIED: not connected to {} and no answer arrived",
+            peer_id
+        ),
+    }
+}
+
+/// Lists the local public songs whose content no responding peer shares.
+async fn report_unique_songs(query: PendingQuery) {
+    let local_songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            error!("error fetching local songs: {}", e);
+            return;
+        }
+    };
+    if query.responses.is_empty() {
+        info!("No responses received, nothing to compare against");
+        return;
+    }
+    let peers = query.responses.len();
+    let shared: HashSet<String> = query
+        .responses
+        .iter()
+        .flat_map(|(_, songs)| songs.iter().map(content_checksum))
+        .collect();
+    let public: Vec<&Song> = local_songs.iter().filter(|s| s.public).collect();
+    let unique: Vec<&Song> = public
+        .iter()
+        .copied()
+        .filter(|s| !shared.contains(&content_checksum(s)))
+        .collect();
+    info!(
+        "{} of your {} public song(s) aren't shared by any of {} peer(s):",
+        unique.len(),
+        public.len(),
+        peers
+    );
+    unique
+        .iter()
+        .for_each(|s| info!("  {}: {} by {}", s.id, s.title, s.artist));
+}
+
+/// Streaming mode: one response of a running `list songs` query, numbered in arrival order.
+fn print_streamed_response(k: usize, source: &str, songs: &Songs) {
+    if std::io::stderr().is_terminal() {
+        eprint!("\r\x1b[K");
+    }
+    info!(
+        "-- response {} from {} ({} songs) --",
+        k,
+        source,
+        songs.len()
+    );
+    songs.iter().for_each(|s| print_song(s, None));
+}
+
+fn print_query_results(query: PendingQuery, sort: Option<SortKey>) {
+    if query.responses.is_empty() {
+        info!("No responses received for {:?}", query.mode);
+        return;
+    }
+    let peers = query.responses.len();
+    let mut seen = HashSet::new();
+    let mut songs: Vec<(String, Song)> = query
+        .responses
+        .into_iter()
+        .flat_map(|(source, songs)| songs.into_iter().map(move |s| (source.clone(), s)))
+        .filter(|(_, song)| seen.insert(song_key(song)))
+        .collect();
+    if CONFIG.stream_responses {
+        info!("Done: {} response(s), {} unique songs", peers, songs.len());
+        return;
+    }
+    if let Some(key) = sort {
+        songs.sort_by(|a, b| key.compare(&a.1, &b.1));
+    }
+    info!("Songs from {} peer(s) ({} unique):", peers, songs.len());
+    if songs.is_empty() {
+        info!("No public songs shared yet");
+    }
+    songs
+        .iter()
+        .for_each(|(source, song)| print_song(song, Some(source)));
+}
+
+/// Saves the songs of a finished clone or seed query, `share` makes the copies public.
+async fn clone_peer_songs(query: PendingQuery, share: bool) {
+    let peer_id = match &query.mode {
+        ListMode::One(peer_id) => peer_id.clone(),
+        ListMode::ALL => "all peers".to_owned(),
+    };
+    if query.responses.is_empty() {
+        info!("No response from {}, nothing cloned", peer_id);
+        return;
+    }
+    let songs = query
+        .responses
+        .into_iter()
+        .flat_map(|(source, songs)| songs.into_iter().map(move |s| (source.clone(), s)))
+        .collect();
+    match save_peer_songs(songs, share).await {
+        Ok(report) if share => info!(
+            "Seeding songs of {}: {} new, {} existing copies made public, {} skipped",
+            peer_id, report.added, report.reshared, report.skipped
+        ),
+        Ok(report) => info!(
+            "Cloned {} song(s) from {}, skipped {} already in your library",
+            report.added, peer_id, report.skipped
+        ),
+        Err(e) => error!("error saving songs from {}: {}", peer_id, e),
+    }
+}
+
+/// Received songs that aren't in the library yet, numbered in this order by `list received`.
+async fn unsaved_received(swarm: &Swarm<SongBehaviour>) -> Result<Vec<(String, Song)>> {
+    let local_songs = read_local_songs().await?;
+    let origins: HashSet<_> = local_songs
+        .iter()
+        .filter_map(|s| s.origin.clone())
+        .collect();
+    let keys: HashSet<_> = local_songs.iter().map(song_key).collect();
+    let mut unsaved: Vec<(String, Song)> = swarm
+        .behaviour()
+        .received
+        .iter()
+        .flat_map(|(source, songs)| songs.iter().map(move |s| (source.clone(), s.clone())))
+        .filter(|(source, song)| {
+            let origin = song.origin.clone().unwrap_or(SongOrigin {
+                peer: source.clone(),
+                id: song.id,
+            });
+            !origins.contains(&origin) && !keys.contains(&song_key(song))
+        })
+        .collect();
+    unsaved.sort_by(|(a, x), (b, y)| a.cmp(b).then(x.id.cmp(&y.id)));
+    Ok(unsaved)
+}
+
+async fn handle_list_received(swarm: &Swarm<SongBehaviour>) -> Result<()> {
+    let unsaved = match unsaved_received(swarm).await {
+        Ok(unsaved) => unsaved,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    info!("Received songs not in your library ({})", unsaved.len());
+    if unsaved.is_empty() {
+        info!("Nothing new, list a peer's songs first");
+    }
+    for (n, (source, song)) in unsaved.iter().enumerate() {
+        print_song(song, Some(&format!("#{} {}", n + 1, source)));
+    }
+    Ok(())
+}
+
+async fn handle_save_received(cmd: &str, swarm: &Swarm<SongBehaviour>) -> Result<()> {
+    let arg = cmd.strip_prefix("save received").unwrap_or_default().trim();
+    let n = match arg.parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            return Err("usage: save received <number from list received>".into());
+        }
+    };
+    let entry = match unsaved_received(swarm).await {
+        Ok(mut unsaved) if n <= unsaved.len() => unsaved.swap_remove(n - 1),
+        Ok(unsaved) => {
+            return Err(format!("no received song #{}, there are {}", n, unsaved.len()).into());
+        }
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let label = format!("{} - {}", entry.1.title.trim(), entry.1.artist.trim());
+    match save_peer_songs(vec![entry], false).await {
+        Ok(report) if report.added > 0 => info!("Saved {} as a private song", label),
+        Ok(_) => info!("{} is already in your library", label),
+        Err(e) => return Err(format!("error saving {}: {}", label, e).into()),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct SaveReport {
+    added: usize,
+    skipped: usize,
+    /// Existing copies that were made public again by a seed.
+    reshared: usize,
+}
+
+/// Adds songs received from peers with fresh ids, skipping ones already in the
+/// library. With `share` the copies are public and existing copies of the same
+/// origin are published too.
+async fn save_peer_songs(songs: Vec<(String, Song)>, share: bool) -> Result<SaveReport> {
+    let _guard = SAVE_LOCK.lock().await;
+    let mut local_songs = read_local_songs().await?;
+    let mut known: HashSet<_> = local_songs.iter().map(song_key).collect();
+    let mut origins: HashSet<_> = local_songs
+        .iter()
+        .filter_map(|s| s.origin.clone())
+        .collect();
+    let mut next_id = local_songs.iter().map(|s| s.id + 1).max().unwrap_or(0);
+    let mut report = SaveReport::default();
+    let local_name = local_name().await;
+    for (source, mut song) in songs {
+        // a song relayed by another peer keeps pointing at where it was first shared
+        let origin = song.origin.take().unwrap_or(SongOrigin {
+            peer: source,
+            id: song.id,
+        });
+        if origins.contains(&origin) {
+            let copy = local_songs
+                .iter_mut()
+                .find(|s| s.origin.as_ref() == Some(&origin));
+            match copy {
+                Some(copy) if share && !copy.public => {
+                    copy.public = true;
+                    copy.updated_at = Some(now_secs());
+                    report.reshared += 1;
+                }
+                _ => report.skipped += 1,
+            }
+            continue;
+        }
+        if !known.insert(song_key(&song)) {
+            report.skipped += 1;
+            continue;
+        }
+        origins.insert(origin.clone());
+        song.origin = Some(origin);
+        song.id = next_id;
+        song.public = share;
+        song.updated_at = Some(now_secs());
+        song.notes.clear();
+        song.shared_with = None;
+        // keep the platform the peer recorded, the song still came from there
+        song.platform
+            .get_or_insert_with(|| PEER_PLATFORM.to_owned());
+        // likewise whoever first added it, the origin already records who shared it
+        song.added_by.get_or_insert_with(|| local_name.clone());
+        next_id += 1;
+        report.added += 1;
+        local_songs.push(song);
+    }
+    if report.added > 0 || report.reshared > 0 {
+        write_local_songs(&local_songs).await?;
+    }
+    Ok(report)
+}
+
+/// The songs `receiver` gets in a response, with the private fields removed. In
+/// `catalog_only` mode lyrics are left out too, along with the checksum covering them.
+fn shared_songs(songs: Songs, receiver: &str, catalog_only: bool) -> Songs {
+    songs
+        .into_iter()
+        .filter(|r| r.visible_to(receiver))
+        .map(|mut r| {
+            r.notes.clear();
+            r.shared_with = None;
+            // a local path means nothing to peers and reveals the local file layout
+            if !r.cover.as_deref().is_some_and(is_url) {
+                r.cover = None;
+            }
+            if catalog_only {
+                r.lyrics.clear();
+                r.checksum = None;
+            }
+            r
+        })
+        .collect()
+}
+
+async fn handle_preview_share(cmd: &str, swarm: &Swarm<SongBehaviour>) -> Result<()> {
+    let peer_id = cmd.strip_prefix("preview share").unwrap_or_default().trim();
+    if !peer_id.is_empty() {
+        if let Err(e) = PeerId::from_str(peer_id) {
+            return Err(format!("invalid peer id: {}, {}", peer_id, e).into());
+        }
+    }
+    let songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let scoped = songs
+        .iter()
+        .filter(|s| s.public && s.shared_with.as_ref().is_some_and(|p| !p.is_empty()))
+        .count();
+    // a peer id previews a targeted request, those always include lyrics
+    let catalog_only = swarm.behaviour().catalog_only && peer_id.is_empty();
+    let data = shared_songs(songs, peer_id, catalog_only);
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&data).expect("can jsonify songs")
+    );
+    if peer_id.is_empty() {
+        info!(
+            "{} song(s) would be sent to any peer, {} more only to the peers they are published to",
+            data.len(),
+            scoped
+        );
+    } else {
+        info!("{} song(s) would be sent to {}", data.len(), peer_id);
+    }
+    Ok(())
+}
+
+fn respond_with_public_songs(
+    sender: mpsc::UnboundedSender<ListResponse>,
+    receiver: String,
+    compress: bool,
+    catalog_only: bool,
+) {
+    tokio::spawn(async move {
+        match read_local_songs().await {
+            Ok(songs) => {
+                let data = shared_songs(songs, &receiver, catalog_only);
+                let mut resp = ListResponse {
+                    mode: ListMode::ALL,
+                    receiver,
+                    data,
+                    public_key: Vec::new(),
+                    signature: Vec::new(),
+                    compress,
+                };
+                resp.sign();
+                if let Err(e) = sender.send(resp) {
+                    error!("error sending response via channel, {}", e);
+                }
+            }
+            Err(e) => error!("error fetching local songs to answer ALL request, {}", e),
+        }
+    });
+}
+
+fn answer_census(sender: mpsc::UnboundedSender<CensusReply>, receiver: String) {
+    tokio::spawn(async move {
+        let public_songs = match read_local_songs().await {
+            Ok(songs) => songs.iter().filter(|s| s.visible_to(&receiver)).count(),
+            Err(e) => {
+                error!("error fetching local songs to answer census, {}", e);
+                return;
+            }
+        };
+        let nick = read_settings().await.ok().and_then(|s| s.nick);
+        let reply = CensusReply {
+            receiver,
+            nick,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            public_songs,
+        };
+        if let Err(e) = sender.send(reply) {
+            error!("error sending census reply via channel, {}", e);
+        }
+    });
+}
+
+impl NetworkBehaviourEventProcess<PingEvent> for SongBehaviour {
+    fn inject_event(&mut self, event: PingEvent) {
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                self.latencies.insert(event.peer, Ok(rtt));
+            }
+            Ok(PingSuccess::Pong) => (),
+            Err(PingFailure::Timeout) => {
+                self.latencies.insert(event.peer, Err("timeout".to_owned()));
+            }
+            Err(e) => {
+                self.latencies.insert(event.peer, Err(e.to_string()));
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for SongBehaviour {
+    fn inject_event(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(discovered_list) => {
+                for (peer, _addr) in discovered_list {
+                    self.last_activity = Instant::now();
+                    self.session.peers_seen.insert(peer);
+                    self.last_seen.insert(peer, now_secs());
+                    // back within the grace period, it never left the partial view
+                    if self.expiring.remove(&peer).is_some() || self.partial_view.contains(&peer) {
+                        continue;
+                    }
+                    if !self.pending_peers.iter().any(|(p, _)| *p == peer) {
+                        let mut due = Instant::now() + join_delay();
+                        if let Some(removed_at) = self.removed_peers.get(&peer) {
+                            due = due.max(*removed_at + CONFIG.rejoin_cooldown);
+                        }
+                        self.pending_peers.push((peer, due));
+                    }
+                }
+            }
+            MdnsEvent::Expired(expired_list) => {
+                for (peer, _addr) in expired_list {
+                    if !self.mdns.has_node(&peer) {
+                        self.pending_peers.retain(|(p, _)| *p != peer);
+                        if self.partial_view.contains(&peer) && !self.pinned_peers.contains(&peer) {
+                            self.expiring
+                                .entry(peer)
+                                .or_insert_with(|| Instant::now() + CONFIG.expire_grace);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn create_new_song(
+    title: &str,
+    artist: &str,
+    lyrics: &str,
+    explicit: bool,
+    cover: Option<&str>,
+) -> Result<()> {
+    // title and artist are shown on one line here and on peers, so they must stay single-line
+    if has_control_chars(title) || has_control_chars(artist) {
+        warn!("removed line breaks and control characters from the title/artist");
+    }
+    let title = strip_control_chars(title);
+    let artist = strip_control_chars(artist);
+    if title.trim().is_empty() || artist.trim().is_empty() {
+        return Err("title and artist can't be empty".into());
+    }
+    let lyrics = strip_lyrics_control_chars(lyrics);
+    let mut local_songs = read_local_songs().await?;
+    let new_id = match local_songs.iter().max_by_key(|r| r.id) {
+        Some(v) => v.id + 1,
+        None => 0,
+    };
+    local_songs.push(Song {
+        id: new_id,
+        title: title.clone(),
+        artist: artist.clone(),
+        lyrics: lyrics.clone(),
+        explicit,
+        public: false,
+        updated_at: Some(now_secs()),
+        checksum: None,
+        origin: None,
+        notes: String::new(),
+        shared_with: None,
+        platform: Some(LOCAL_PLATFORM.to_owned()),
+        cover: cover.map(str::to_owned),
+        added_by: Some(local_name().await),
+    });
+    write_local_songs(&local_songs).await?;
+
+    info!("Created song:");
+    info!("Title: {}", title);
+    info!("Artist: {}", artist);
+    info!("Lyrics:: {}", lyrics);
+    info!("Explicit:: {}", explicit);
+
+    Ok(())
+}
+
+/// Makes a song public, to everyone or only to `shared_with` when given. Returns whether a song
+/// with the given id was found.
+async fn publish_song(id: usize, shared_with: Option<Vec<String>>) -> Result<bool> {
+    let mut local_songs = read_local_songs().await?;
+    let mut found = false;
+    local_songs.iter_mut().filter(|r| r.id == id).for_each(|r| {
+        r.public = true;
+        r.shared_with = shared_with.clone();
+        r.updated_at = Some(now_secs());
+        found = true;
+    });
+    if found {
+        write_local_songs(&local_songs).await?;
+    }
+    Ok(found)
+}
+
+/// Sets the public flag on every song by `artist` (case-insensitive).
+/// Returns how many songs are by `artist` and the ids of the ones whose visibility changed.
+async fn set_artist_public(artist: &str, public: bool) -> Result<(usize, Vec<usize>)> {
+    let mut local_songs = read_local_songs().await?;
+    let artist = artist.to_lowercase();
+    let mut matched = 0;
+    let mut changed = Vec::new();
+    for song in local_songs
+        .iter_mut()
+        .filter(|s| s.artist.trim().to_lowercase() == artist)
+    {
+        matched += 1;
+        if song.public != public {
+            song.public = public;
+            song.updated_at = Some(now_secs());
+            changed.push(song.id);
+        }
+    }
+    if !changed.is_empty() {
+        write_local_songs(&local_songs).await?;
+    }
+    Ok((matched, changed))
+}
+
+/// Folds the songs with `ids` into the first one and removes the others. The kept song's empty
+/// lyrics, cover and notes are filled from them, and it becomes public, with the same audience,
+/// if it wasn't and one of them was. Returns the removed songs. The library may have changed
+/// since the user confirmed, so the group is looked up again and nothing is merged if it differs.
+async fn merge_songs(ids: &[usize]) -> Result<Songs> {
+    let (keep, others) = ids.split_first().ok_or("no songs to merge")?;
+    let _guard = SAVE_LOCK.lock().await;
+    let local_songs = read_local_songs().await?;
+    let still_grouped = duplicate_groups(&local_songs)
+        .iter()
+        .any(|g| g.iter().map(|s| s.id).eq(ids.iter().copied()));
+    if !still_grouped {
+        return Err("the duplicate group changed since it was listed, nothing merged; run 'find duplicates' again".into());
+    }
+    let (mut removed, mut local_songs): (Songs, Songs) = local_songs
+        .into_iter()
+        .partition(|s| others.contains(&s.id));
+    let target = local_songs
+        .iter_mut()
+        .find(|s| s.id == *keep)
+        .ok_or_else(|| format!("song {} no longer exists", keep))?;
+    removed.sort_by_key(|s| s.id);
+    for song in &removed {
+        if target.lyrics.trim().is_empty() {
+            target.lyrics = song.lyrics.clone();
+        }
+        if target.cover.is_none() {
+            target.cover = song.cover.clone();
+        }
+        if target.notes.is_empty() {
+            target.notes = song.notes.clone();
+        }
+        if song.public && !target.public {
+            target.public = true;
+            target.shared_with = song.shared_with.clone();
+        }
+    }
+    target.updated_at = Some(now_secs());
+    write_local_songs(&local_songs).await?;
+    Ok(removed)
+}
+
+/// Appends a line to a song's private notes, `--clear` empties them.
+async fn add_note(id: usize, text: &str) -> Result<bool> {
+    let mut local_songs = read_local_songs().await?;
+    let song = match local_songs.iter_mut().find(|s| s.id == id) {
+        Some(song) => song,
+        None => return Ok(false),
+    };
+    if text == "--clear" {
+        song.notes.clear();
+    } else {
+        if !song.notes.is_empty() {
+            song.notes.push('\n');
+        }
+        song.notes.push_str(&strip_control_chars(text));
+    }
+    write_local_songs(&local_songs).await?;
+    Ok(true)
+}
+
+async fn read_local_songs() -> Result<Songs> {
+    let content = match fs::read(&*STORAGE_FILE_PATH).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Songs::new()),
+        Err(e) => return Err(StorageError::from_io(e, "reading", &STORAGE_FILE_PATH).into()),
+    };
+    let mut result: Songs = serde_json::from_slice(&content)?;
+    let legacy = legacy_explicit_values(&content);
+    let mut explicit_warned = EXPLICIT_WARNED.lock().expect("explicit warned lock");
+    for (song, spelling) in result.iter_mut().zip(legacy) {
+        let spelling = match spelling {
+            Some(spelling) => spelling,
+            None => continue,
+        };
+        if song.checksum.as_deref() == Some(checksum_with_explicit(song, &spelling).as_str()) {
+            song.checksum = Some(content_checksum(song));
+        }
+        if parse_explicit(&spelling).is_none() && explicit_warned.insert(song.id) {
+            warn!(
+                "song {} has an unrecognized explicit value {:?}, read as clean",
+                song.id, spelling
+            );
+        }
+    }
+    let mut warned = CHECKSUM_WARNED.lock().expect("checksum warned lock");
+    for song in result.iter().filter(|s| has_bad_checksum(s)) {
+        if !warned.insert(song.id) {
+            continue;
+        }
+        warn!(
+            "song {} doesn't match its checksum, it may be corrupted; run 'validate --fix' if it was edited on purpose",
+            song.id
+        );
+    }
+    Ok(result)
+}
+
+/// The nickname from `set nick`, or the peer id without one, recorded in `added_by`.
+async fn local_name() -> String {
+    match read_settings().await {
+        Ok(Settings {
+            nick: Some(nick), ..
+        }) => nick,
+        _ => PEER_ID.to_string(),
+    }
+}
+
+async fn read_settings() -> Result<Settings> {
+    match fs::read(&*SETTINGS_FILE_PATH).await {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Settings::default()),
+        Err(e) => Err(StorageError::from_io(e, "reading", &SETTINGS_FILE_PATH).into()),
+    }
+}
+
+/// Attempts at writing a file before giving up on transient errors.
+const WRITE_ATTEMPTS: u32 = 3;
+
+/// Writes a file, retrying with a jittered backoff when the error looks transient.
+async fn write_with_retry(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match fs::write(path, contents).await {
+            Ok(()) => return Ok(()),
+            Err(e)
+                if attempt < WRITE_ATTEMPTS
+                    && matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::TimedOut
+                    ) =>
+            {
+                let backoff = 50 * 2u64.pow(attempt - 1) + rand::thread_rng().gen_range(0..50);
+                warn!(
+                    "writing {} failed ({}), retrying in {}ms",
+                    path.display(),
+                    e,
+                    backoff
+                );
+                time::sleep(Duration::from_millis(backoff)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn write_settings(settings: &Settings) -> Result<()> {
+    let json = serde_json::to_string(settings)?;
+    write_with_retry(&SETTINGS_FILE_PATH, &json)
+        .await
+        .map_err(|e| StorageError::from_io(e, "writing", &SETTINGS_FILE_PATH))?;
+    Ok(())
+}
+
+fn strip_control_chars(s: &str) -> String {
+    s.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Interprets the loosely typed `explicit` field, `None` if the value isn't recognised.
+fn parse_explicit(value: &str) -> Option<bool> {
+    let value = value.trim().to_lowercase();
+    if matches!(value.as_str(), "true" | "t" | "yes" | "y" | "1") {
+        Some(true)
+    } else if matches!(value.as_str(), "false" | "f" | "no" | "n" | "0") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// The explicit field of `create song`, stored as `true`/`false`. Missing or empty keeps the
+/// default, so a cover can follow it.
+fn explicit_field(value: Option<&str>, default: bool) -> std::result::Result<bool, String> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        None => Ok(default),
+        Some(value) => parse_explicit(value).ok_or_else(|| {
+            format!(
+                "invalid explicit value {:?}, use true/false, yes/no, y/n or 1/0",
+                value
+            )
+        }),
+    }
+}
+
+/// `explicit` is stored and sent as "true"/"false", peers that predate the bool expect a string.
+fn serialize_explicit<S: serde::Serializer>(
+    explicit: &bool,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(if *explicit { "true" } else { "false" })
+}
+
+/// Accepts a bool or any spelling `parse_explicit` knows. Older versions stored whatever was
+/// typed, unrecognised values read as clean rather than making the library unreadable.
+fn deserialize_explicit<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Bool(bool),
+        Text(String),
+    }
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::Bool(explicit) => explicit,
+        Stored::Text(value) => parse_explicit(&value).unwrap_or(false),
+    })
+}
+
+/// The `explicit` values of a songs file that aren't spelled "true"/"false", by position.
+fn legacy_explicit_values(content: &[u8]) -> Vec<Option<String>> {
+    #[derive(Deserialize)]
+    struct Stored {
+        explicit: serde_json::Value,
+    }
+    serde_json::from_slice::<Vec<Stored>>(content)
+        .map(|songs| {
+            songs
+                .into_iter()
+                .map(|s| match s.explicit {
+                    serde_json::Value::String(v) if v != "true" && v != "false" => Some(v),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn has_control_chars(s: &str) -> bool {
+    s.chars().any(char::is_control)
+}
+
+/// Lyrics may span several lines, so newlines and tabs are allowed there.
+fn has_lyrics_control_chars(s: &str) -> bool {
+    s.chars().any(|c| c.is_control() && c != '\n' && c != '\t')
+}
+
+fn strip_lyrics_control_chars(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect()
+}
+
+fn validate_songs(songs: &[Song]) -> Vec<(usize, String)> {
+    let mut issues = Vec::new();
+    let mut ids = HashSet::new();
+    for song in songs {
+        let mut report = |problem: String| issues.push((song.id, problem));
+        if !ids.insert(song.id) {
+            report("duplicate id".to_owned());
+        }
+        if song.title.trim().is_empty() {
+            report("empty title".to_owned());
+        }
+        if song.artist.trim().is_empty() {
+            report("empty artist".to_owned());
+        }
+        if song.lyrics.chars().count() > MAX_LYRICS_LEN {
+            report(format!("lyrics longer than {} characters", MAX_LYRICS_LEN));
+        }
+        if has_control_chars(&song.title) || has_control_chars(&song.artist) {
+            report("control characters in title or artist".to_owned());
+        }
+        if has_lyrics_control_chars(&song.lyrics) {
+            report("control characters in lyrics".to_owned());
+        }
+        if has_bad_checksum(song) {
+            report("content doesn't match checksum".to_owned());
+        }
+    }
+    issues
+}
+
+/// Runs `edit all` in the background so peers are still served while the editor is open.
+fn handle_edit_all(swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let behaviour = swarm.behaviour_mut();
+    if behaviour.editing {
+        info!("edit all is already open");
+        return Ok(());
+    }
+    behaviour.editing = true;
+    let done = behaviour.edit_sender.clone();
+    tokio::spawn(async move {
+        edit_all().await;
+        let _ = done.send(());
+    });
+    Ok(())
+}
+
+/// Opens the whole library in `$VISUAL`/`$EDITOR` and saves the result if it is valid.
+/// The edit file is kept when it isn't, so the changes aren't lost.
+async fn edit_all() {
+    let original = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            error!("error fetching local songs: {}", e);
+            return;
+        }
+    };
+    let path = CONFIG.data_dir.join(EDIT_FILE_NAME);
+    // checksums are recomputed on save, editing them only causes false corruption warnings
+    let editable: Songs = original
+        .iter()
+        .cloned()
+        .map(|mut s| {
+            s.checksum = None;
+            s
+        })
+        .collect();
+    let json = serde_json::to_string_pretty(&editable).expect("can jsonify songs");
+    if let Err(e) = fs::write(&path, &json).await {
+        error!("can't write {}: {}", path.display(), e);
+        return;
+    }
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    info!(
+        "Opening {} in {}, save and quit to apply",
+        path.display(),
+        editor
+    );
+    let editor_path = path.clone();
+    // the editor has the terminal, log lines from the network would draw over it
+    hold_log_lines();
+    let status = tokio::task::spawn_blocking(move || {
+        let mut words = editor.split_whitespace();
+        std::process::Command::new(words.next().unwrap_or("vi"))
+            .args(words)
+            .arg(&editor_path)
+            .status()
+    })
+    .await
+    .expect("editor task doesn't panic");
+    release_log_lines();
+    match status {
+        Ok(status) if status.success() => (),
+        Ok(status) => {
+            error!("editor exited with {}, library unchanged", status);
+            return;
+        }
+        Err(e) => {
+            error!("can't start the editor: {}, set $EDITOR", e);
+            return;
+        }
+    }
+    let edited_json = match fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(e) => {
+            error!("can't read {}: {}, library unchanged", path.display(), e);
+            return;
+        }
+    };
+    if edited_json == json {
+        info!("No changes");
+        let _ = fs::remove_file(&path).await;
+        return;
+    }
+    let mut edited: Songs = match serde_json::from_str(&edited_json) {
+        Ok(songs) => songs,
+        Err(e) => {
+            error!(
+                "{} isn't a valid song list: {}, library unchanged (your edits are kept there)",
+                path.display(),
+                e
+            );
+            return;
+        }
+    };
+    let issues = validate_songs(&edited);
+    let duplicates: Vec<usize> = issues
+        .iter()
+        .filter(|(_, problem)| problem == "duplicate id")
+        .map(|(id, _)| *id)
+        .collect();
+    if !duplicates.is_empty() {
+        error!(
+            "duplicate song ids {:?} in {}, library unchanged (your edits are kept there)",
+            duplicates,
+            path.display()
+        );
+        return;
+    }
+    for (id, problem) in &issues {
+        warn!("song {}: {}", id, problem);
+    }
+    let before: HashMap<usize, serde_json::Value> = editable
+        .iter()
+        .map(|s| (s.id, serde_json::to_value(s).expect("can jsonify song")))
+        .collect();
+    let checksums: HashMap<usize, Option<String>> = original
+        .iter()
+        .map(|s| (s.id, s.checksum.clone()))
+        .collect();
+    let mut changed = 0;
+    for song in edited.iter_mut() {
+        song.checksum = None;
+        let value = serde_json::to_value(&*song).expect("can jsonify song");
+        if before.get(&song.id) != Some(&value) {
+            song.updated_at = Some(now_secs());
+            changed += 1;
+        } else {
+            // an untouched song keeps its checksum, even a mismatched one
+            song.checksum = checksums.get(&song.id).cloned().flatten();
+        }
+    }
+    let kept: HashSet<usize> = edited.iter().map(|s| s.id).collect();
+    let removed = original.iter().filter(|s| !kept.contains(&s.id)).count();
+    let _guard = SAVE_LOCK.lock().await;
+    match read_local_songs().await {
+        Ok(current) if current == original => (),
+        Ok(_) => {
+            error!(
+                "the library changed while the editor was open, library unchanged (your edits are kept in {})",
+                path.display()
+            );
+            return;
+        }
+        Err(e) => {
+            error!(
+                "error fetching local songs: {}, your edits are kept in {}",
+                e,
+                path.display()
+            );
+            return;
+        }
+    }
+    match write_local_songs(&edited).await {
+        Ok(()) => {
+            info!(
+                "Saved {} songs ({} changed or added, {} removed)",
+                edited.len(),
+                changed,
+                removed
+            );
+            let _ = fs::remove_file(&path).await;
+        }
+        Err(e) => error!(
+            "error saving songs: {}, your edits are kept in {}",
+            e,
+            path.display()
+        ),
+    }
+}
+
+/// Repairs what can be fixed without losing data, returns the number of songs changed.
+fn fix_songs(songs: &mut Songs) -> usize {
+    let mut next_id = songs.iter().map(|s| s.id + 1).max().unwrap_or(0);
+    let mut ids = HashSet::new();
+    let mut fixed = 0;
+    for song in songs.iter_mut() {
+        // a mismatched checksum is replaced below, accepting the current content
+        let mut changed = has_bad_checksum(song);
+        if !ids.insert(song.id) {
+            song.id = next_id;
+            ids.insert(next_id);
+            next_id += 1;
+            changed = true;
+        }
+        if has_control_chars(&song.title) || has_control_chars(&song.artist) {
+            song.title = strip_control_chars(&song.title);
+            song.artist = strip_control_chars(&song.artist);
+            changed = true;
+        }
+        if has_lyrics_control_chars(&song.lyrics) {
+            song.lyrics = strip_lyrics_control_chars(&song.lyrics);
+            changed = true;
+        }
+        if changed {
+            song.checksum = Some(content_checksum(song));
+            song.updated_at = Some(now_secs());
+            fixed += 1;
+        }
+    }
+    fixed
+}
+
+/// Song count per artist, grouped case-insensitively and named with the first spelling seen.
+fn artist_counts(songs: &[Song]) -> Vec<(String, usize)> {
+    let mut artists: HashMap<String, (String, usize)> = HashMap::new();
+    for song in songs {
+        let name = song.artist.trim();
+        artists
+            .entry(name.to_lowercase())
+            .or_insert_with(|| (name.to_owned(), 0))
+            .1 += 1;
+    }
+    artists.into_values().collect()
+}
+
+fn compute_stats(songs: &[Song]) -> LibraryStats {
+    let mut top_artists = artist_counts(songs);
+    top_artists.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    top_artists.truncate(TOP_ARTISTS);
+    let public = songs.iter().filter(|s| s.public).count();
+    let explicit = songs.iter().filter(|s| s.explicit).count();
+    let ratio = |n: usize| {
+        if songs.is_empty() {
+            0.0
+        } else {
+            n as f64 / songs.len() as f64
+        }
+    };
+    let lyrics_chars = songs.iter().map(|s| s.lyrics.trim().chars().count()).sum();
+    let title_len = |s: &&Song| s.title.trim().chars().count();
+    LibraryStats {
+        total: songs.len(),
+        public,
+        private: songs.len() - public,
+        explicit,
+        explicit_ratio: ratio(explicit),
+        average_lyrics_chars: ratio(lyrics_chars),
+        shortest_title: songs
+            .iter()
+            .min_by_key(title_len)
+            .map(|s| s.title.trim().to_owned()),
+        longest_title: songs
+            .iter()
+            .max_by_key(title_len)
+            .map(|s| s.title.trim().to_owned()),
+        missing_lyrics: songs.iter().filter(|s| s.lyrics.trim().is_empty()).count(),
+        top_artists,
+    }
+}
+
+/// Prints one song of a listing in the current display mode, prefixed with the peer it came from.
+fn print_song(song: &Song, source: Option<&str>) {
+    let prefix = source.map(|s| format!("{}: ", s)).unwrap_or_default();
+    let explicit = if song.explicit { "explicit" } else { "clean" };
+    let visibility = if song.public { "public" } else { "private" };
+    if !VERBOSE.load(AtomicOrdering::Relaxed) {
+        info!(
+            "{}{}: {} - {} ({}, {})",
+            prefix,
+            song.id,
+            song.title.trim(),
+            song.artist.trim(),
+            explicit,
+            visibility
+        );
+        return;
+    }
+    info!(
+        "{}Song {}: {} - {}",
+        prefix,
+        song.id,
+        song.title.trim(),
+        song.artist.trim()
+    );
+    info!("  Content: {}", explicit);
+    info!("  Visibility: {}", visibility);
+    if let Some(updated_at) = song.updated_at {
+        info!("  Updated: {}", updated_at);
+    }
+    if let Some(origin) = &song.origin {
+        info!("  Origin: song {} of peer {}", origin.id, origin.peer);
+    }
+    if let Some(platform) = &song.platform {
+        info!("  Platform: {}", platform);
+    }
+    if let Some(cover) = &song.cover {
+        info!("  Cover: {}", cover);
+    }
+    if let Some(added_by) = &song.added_by {
+        info!("  Added by: {}", added_by);
+    }
+    if let Some(peers) = song.shared_with.as_ref().filter(|p| !p.is_empty()) {
+        info!("  Shared with: {}", peers.join(", "));
+    }
+    info!("  Lyrics:");
+    song.lyrics.lines().for_each(|l| info!("    {}", l));
+}
+
+/// A human readable card for sharing one song outside the network.
+fn format_song_card(song: &Song) -> String {
+    let explicit = if song.explicit { "yes" } else { "no" };
+    format!(
+        "{} - {}\nExplicit: {}\n\n{}\n",
+        song.title.trim(),
+        song.artist.trim(),
+        explicit,
+        song.lyrics.trim()
+    )
+}
+
+fn validate_nick(name: &str) -> std::result::Result<String, String> {
+    let nick = strip_control_chars(name).trim().to_owned();
+    if nick.is_empty() {
+        Err("nickname can't be empty".to_owned())
+    } else if nick.chars().count() > MAX_NICK_LEN {
+        Err(format!(
+            "nickname can't be longer than {} characters",
+            MAX_NICK_LEN
+        ))
+    } else {
+        Ok(nick)
+    }
+}
+
+/// Songs are written in id order so the file stays stable no matter how it was edited.
+/// Checksums are only computed for songs that are new, have none or whose content differs from
+/// the file, so saving never accepts an unrelated corrupted song; that's up to `validate --fix`.
+async fn write_local_songs(songs: &Songs) -> Result<()> {
+    // an unreadable file only gets checksums added where they are missing
+    let stored: Option<HashMap<usize, Song>> = match fs::read(&*STORAGE_FILE_PATH).await {
+        Ok(content) => serde_json::from_slice::<Songs>(&content)
+            .ok()
+            .map(|songs| songs.into_iter().map(|s| (s.id, s)).collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Some(HashMap::new()),
+        Err(_) => None,
+    };
+    let mut sorted = songs.clone();
+    sorted.sort_by_key(|s| s.id);
+    for song in sorted.iter_mut() {
+        let changed = match &stored {
+            Some(stored) => stored
+                .get(&song.id)
+                .is_none_or(|s| content_checksum(s) != content_checksum(song)),
+            None => false,
+        };
+        if changed || song.checksum.is_none() {
+            song.checksum = Some(content_checksum(song));
+        }
+    }
+    let json = serde_json::to_string(&sorted)?;
+    write_with_retry(&STORAGE_FILE_PATH, &json)
+        .await
+        .map_err(|e| StorageError::from_io(e, "writing", &STORAGE_FILE_PATH))?;
+    *LAST_WRITTEN.lock().expect("last written lock") = Some(json);
+    Ok(())
+}
+
+/// A log line formatted while held back, replayed once the terminal is free again.
+struct HeldLogLine {
+    level: log::Level,
+    target: String,
+    message: String,
+}
+
+/// The most lines held back at once, later ones are only counted.
+const MAX_HELD_LOG_LINES: usize = 10_000;
+
+#[derive(Default)]
+struct HeldLog {
+    lines: Vec<HeldLogLine>,
+    dropped: usize,
+}
+
+/// The pretty_env_logger logger, holding lines back while the terminal is taken.
+struct HoldingLogger(Box<dyn log::Log>);
+
+impl log::Log for HoldingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Some(held) = HELD_LOG_LINES.lock().expect("held log lock").as_mut() {
+            if !self.0.enabled(record.metadata()) {
+                return;
+            }
+            if held.lines.len() >= MAX_HELD_LOG_LINES {
+                held.dropped += 1;
+            } else {
+                held.lines.push(HeldLogLine {
+                    level: record.level(),
+                    target: record.target().to_owned(),
+                    message: record.args().to_string(),
+                });
+            }
+            return;
+        }
+        self.0.log(record);
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+/// Holds log lines back until `release_log_lines`, for when another program has the terminal.
+fn hold_log_lines() {
+    *HELD_LOG_LINES.lock().expect("held log lock") = Some(HeldLog::default());
+}
+
+/// Prints the log lines held back since `hold_log_lines`, and prints new ones directly again.
+fn release_log_lines() {
+    let held = HELD_LOG_LINES
+        .lock()
+        .expect("held log lock")
+        .take()
+        .unwrap_or_default();
+    for line in &held.lines {
+        log::logger().log(
+            &log::Record::builder()
+                .level(line.level)
+                .target(&line.target)
+                .args(format_args!("{}", line.message))
+                .build(),
+        );
+    }
+    if held.dropped > 0 {
+        warn!(
+            "{} more log lines were dropped while the editor was open",
+            held.dropped
+        );
+    }
+}
+
+/// Like `pretty_env_logger::init`, wrapped so `edit all` can hold lines back.
+fn init_logger() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    let logger = builder.build();
+    let level = logger.filter();
+    log::set_boxed_logger(Box::new(HoldingLogger(Box::new(logger))))
+        .expect("logger is only set once");
+    log::set_max_level(level);
+}
+
+#[tokio::main]
+async fn main() {
+    init_logger();
+
+    if !CONFIG.quiet {
+        print_banner();
+    }
+    if let Err(e) = std::fs::create_dir_all(&CONFIG.data_dir) {
+        error!(
+            "can't create data directory {}: {}",
+            CONFIG.data_dir.display(),
+            e
+        );
+    } else if let Err(e) = check_writable(&CONFIG.data_dir) {
+        warn!(
+            "data directory {} isn't writable ({}), songs and settings can't be saved; use --data-dir to pick another one",
+            CONFIG.data_dir.display(),
+            e
+        );
+    }
+    let _identity_lock = if CONFIG.persist_identity {
+        match IdentityLock::acquire() {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                error!("**************************************************************");
+                error!("{}", e);
+                error!("Two nodes with the same identity.key share one peer id and");
+                error!("confuse everyone else. Use a different --data-dir or --profile.");
+                error!("**************************************************************");
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    info!("Peer Id: {}", *PEER_ID);
+    match read_settings().await {
+        Ok(settings) => VERBOSE.store(
+            settings.display == Some(DisplayMode::Verbose),
+            AtomicOrdering::Relaxed,
+        ),
+        Err(e) => error!("error reading settings: {}", e),
+    }
+    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
+    let (census_sender, mut census_rcv) = mpsc::unbounded_channel();
+    let (edit_sender, mut edit_rcv) = mpsc::unbounded_channel();
+
+    let transp = if CONFIG.insecure {
+        warn!("**************************************************************");
+        warn!("SPOTIFY_BLOG_INSECURE is set: connections are NOT encrypted or");
+        warn!("authenticated. Only use this for local testing on loopback.");
+        warn!("Nodes in this mode can't talk to nodes using encryption.");
+        warn!("**************************************************************");
+        TokioTcpConfig::new()
+            .upgrade(upgrade::Version::V1)
+            .authenticate(PlainText2Config {
+                local_public_key: KEYS.public(),
+            })
+            .multiplex(mplex::MplexConfig::new())
+            .timeout(CONFIG.handshake_timeout)
+            .boxed()
+    } else {
+        let auth_keys = Keypair::<X25519Spec>::new()
+            .into_authentic(&KEYS)
+            .expect("can create auth keys");
+
+        TokioTcpConfig::new()
+            .upgrade(upgrade::Version::V1)
+            .authenticate(NoiseConfig::xx(auth_keys).into_authenticated()) // XX Handshake pattern, IX exists as well and IK - only XX currently provides interop with other libp2p impls
+            .multiplex(mplex::MplexConfig::new())
+            .timeout(CONFIG.handshake_timeout)
+            .boxed()
+    };
+
+    let mut behaviour = SongBehaviour {
+        floodsub: Floodsub::new(*PEER_ID),
+        mdns: Mdns::new(MdnsConfig::default())
+            .await
+            .expect("can create mdns"),
+        ping: Ping::new(PingConfig::new().with_interval(CONFIG.ping_interval)),
+        response_sender,
+        census_sender,
+        edit_sender,
+        editing: false,
+        queries: Vec::new(),
+        nicknames: HashMap::new(),
+        pending_peers: Vec::new(),
+        confirmation: None,
+        latencies: HashMap::new(),
+        last_activity: Instant::now(),
+        session: SessionStats::default(),
+        auto_respond: true,
+        catalog_only: false,
+        last_seen: HashMap::new(),
+        library_watch: LibraryWatch::new(),
+        recent: Vec::new(),
+        partial_view: HashSet::new(),
+        expiring: HashMap::new(),
+        pinned_peers: HashSet::new(),
+        removed_peers: HashMap::new(),
+        topics: CONFIG.topics.iter().map(Topic::new).collect(),
+        received: HashMap::new(),
+        followed: HashMap::new(),
+        census: None,
+    };
+
+    time::sleep(join_delay()).await;
+    for topic in behaviour.topics.clone() {
+        behaviour.floodsub.subscribe(topic);
+    }
+    info!("Subscribed to: {}", behaviour.topic_names().join(", "));
+
+    let limits = ConnectionLimits::default()
+        .with_max_established_incoming(CONFIG.max_connections)
+        .with_max_established_outgoing(CONFIG.max_connections)
+        .with_max_pending_incoming(CONFIG.max_pending_connections)
+        .with_max_pending_outgoing(CONFIG.max_pending_connections);
+    let mut swarm = SwarmBuilder::new(transp, behaviour, *PEER_ID)
+        .connection_limits(limits)
+        .executor(Box::new(|fut| {
+            tokio::spawn(fut);
+        }))
+        .build();
+
+    let (mut stdin_rcv, stdin_resume) = spawn_stdin_reader();
+
+    let listen = if CONFIG.listen.is_empty() {
+        vec!["/ip4/0.0.0.0/tcp/0".to_owned()]
+    } else {
+        CONFIG.listen.clone()
+    };
+    let mut listening = 0;
+    let mut mdns_listener = None;
+    for addr in &listen {
+        match addr.parse::<Multiaddr>() {
+            Ok(addr) => match Swarm::listen_on(&mut swarm, addr.clone()) {
+                Ok(id) => {
+                    listening += 1;
+                    mdns_listener.get_or_insert((id, addr));
+                }
+                Err(e) => error!("can't listen on {}: {}", addr, e),
+            },
+            Err(e) => error!("invalid listen address {}: {}", addr, e),
+        }
+    }
+    if listening == 0 {
+        error!("no usable listen address, exiting");
+        return;
+    }
+
+    match read_settings().await {
+        Ok(settings) => {
+            for pin in &settings.pins {
+                dial_pin(&mut swarm, pin);
+            }
+        }
+        Err(e) => error!("error reading settings, pinned peers not dialed: {}", e),
+    }
+
+    if let Some(path) = &CONFIG.script {
+        if let Err(e) = run_script(path, &mut swarm).await {
+            error!("script {} stopped: {}", path.display(), e);
+        }
+    }
+
+    let mut discovery_timer = time::interval(CONFIG.discovery_interval);
+    let mut discovery_round = 0;
+    if CONFIG.quiet {
+        info!("Ready");
+    } else {
+        print_help();
+    }
+    show_prompt();
+    let mut tick_timer = time::interval(Duration::from_millis(500));
+
+    loop {
+        let evt = {
+            tokio::select! {
+                Some(line) = stdin_rcv.recv() => Some(match line {
+                    Some(line) => EventType::Input(line),
+                    None => EventType::Quit,
+                }),
+                Some(()) = edit_rcv.recv() => Some(EventType::EditDone),
+                // a closed channel yields None, which disables this branch instead of panicking
+                Some(response) = response_rcv.recv() => Some(EventType::Response(response)),
+                Some(reply) = census_rcv.recv() => Some(EventType::CensusReply(reply)),
+                _ = discovery_timer.tick(), if discovery_round < CONFIG.discovery_retries => Some(EventType::DiscoveryTick),
+                _ = tick_timer.tick() => Some(EventType::Tick),
+                event = swarm.select_next_some() => {
+                    swarm.behaviour_mut().last_activity = Instant::now();
+                    match event {
+                        SwarmEvent::NewListenAddr { address, .. } => info!("Listening on {}", address),
+                        SwarmEvent::IncomingConnectionError {
+                            send_back_addr,
+                            error: PendingConnectionError::ConnectionLimit(limit),
+                            ..
+                        } => warn!("Rejected connection from {}: connection limit reached ({})", send_back_addr, limit),
+                        SwarmEvent::UnreachableAddr {
+                            peer_id,
+                            error: PendingConnectionError::ConnectionLimit(limit),
+                            ..
+                        } => warn!("Not connecting to {}: connection limit reached ({})", peer_id, limit),
+                        SwarmEvent::UnreachableAddr { peer_id, address, error, .. } => {
+                            warn!("Couldn't connect to {} at {}: {}", peer_id, address, error)
+                        }
+                        event => info!("Unhandled Swarm Event: {:?}", event),
+                    }
+                    None
+                },
+            }
+        };
+
+        if let Some(event) = evt {
+            match event {
+                EventType::Response(resp) => {
+                    let session = &mut swarm.behaviour_mut().session;
+                    session.requests_answered += 1;
+                    session.songs_served += resp.data.len();
+                    let data = encode_response(&resp);
+                    if data.len() > FLOODSUB_MAX_PACKET {
+                        warn!(
+                            "Response to {} is {} bytes, floodsub drops messages over {} bytes so it likely won't arrive{}",
+                            resp.receiver,
+                            data.len(),
+                            FLOODSUB_MAX_PACKET,
+                            if resp.compress { "" } else { " (the requester can ask for --compress)" }
+                        );
+                    }
+                    swarm.behaviour_mut().publish(data);
+                }
+                EventType::CensusReply(reply) => {
+                    let json = serde_json::to_string(&reply).expect("can jsonify census reply");
+                    swarm.behaviour_mut().publish(json.as_bytes());
+                }
+                EventType::DiscoveryTick => {
+                    discovery_round += 1;
+                    if let Some((id, addr)) = &mdns_listener {
+                        query_mdns_now(&mut swarm, *id, addr);
+                    }
+                    if !CONFIG.quiet {
+                        report_discovery_progress(&swarm, discovery_round);
+                        show_prompt();
+                    }
+                }
+                EventType::Tick => {
+                    swarm.behaviour_mut().on_tick();
+                    if CONFIG.watch && swarm.behaviour_mut().library_watch.poll() {
+                        reload_library().await;
+                    }
+                    if let Some(timeout) = CONFIG.idle_timeout {
+                        let behaviour = swarm.behaviour();
+                        if !behaviour.editing && behaviour.last_activity.elapsed() >= timeout {
+                            info!("Idle for {}s, exiting", timeout.as_secs());
+                            break;
+                        }
+                    }
+                }
+                EventType::Quit => break,
+                EventType::Input(line) if line.trim() == "quit" => break,
+                EventType::Input(line) => {
+                    swarm.behaviour_mut().last_activity = Instant::now();
+                    if let Err(e) = handle_command(&line, &mut swarm).await {
+                        error!("{}", e);
+                    }
+                    if !swarm.behaviour().editing {
+                        let _ = stdin_resume.send(());
+                        show_prompt();
+                    }
+                }
+                EventType::EditDone => {
+                    let behaviour = swarm.behaviour_mut();
+                    behaviour.editing = false;
+                    behaviour.last_activity = Instant::now();
+                    let _ = stdin_resume.send(());
+                    show_prompt();
+                }
+            }
+        }
+    }
+
+    if !CONFIG.quiet {
+        print_session_summary(&swarm.behaviour().session);
+    }
+}
+
+fn print_session_summary(session: &SessionStats) {
+    info!("Session summary:");
+    info!("  requests answered: {}", session.requests_answered);
+    info!("  songs served: {}", session.songs_served);
+    info!("  peers seen: {}", session.peers_seen.len());
+}
+
+/// Reads stdin on its own thread, one line at a time. After each line it waits for the main
+/// loop to send on the returned channel, so nothing reads the terminal while `edit all` has
+/// handed it to an editor. `None` means stdin was closed.
+fn spawn_stdin_reader() -> (
+    mpsc::UnboundedReceiver<Option<String>>,
+    std::sync::mpsc::Sender<()>,
+) {
+    let (line_sender, line_rcv) = mpsc::unbounded_channel();
+    let (resume_sender, resume_rcv) = std::sync::mpsc::channel();
+    std::thread::spawn(move || loop {
+        let mut line = String::new();
+        match std::io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                let _ = line_sender.send(None);
+                return;
+            }
+            Ok(_) => {
+                let line = line.trim_end_matches(['\n', '\r']).to_owned();
+                if line_sender.send(Some(line)).is_err() || resume_rcv.recv().is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    (line_rcv, resume_sender)
+}
+
+/// Runs the non-empty, non-`#` lines of a file as commands, echoing each one.
+async fn run_script(path: &Path, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let content = fs::read_to_string(path).await?;
+    let commands = content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'));
+    for command in commands {
+        info!("> {}", command);
+        if normalize_command(command) == "edit all" {
+            error!("edit all needs the terminal, skipping it in a script");
+            if CONFIG.continue_on_error {
+                continue;
+            }
+            return Err("edit all can't run from a script".into());
+        }
+        if let Err(e) = handle_command(command, swarm).await {
+            error!("{}", e);
+            if !CONFIG.continue_on_error {
+                return Err(format!("command failed: {}", command).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Commands whose arguments are free text and keep their spacing as typed.
+const FREE_TEXT_COMMANDS: &[&str] = &["create song", "note"];
+
+/// Trims a command and collapses runs of whitespace, so `list songs  all` or a trailing tab
+/// parse like `list songs all`. The arguments of free text commands are left untouched.
+fn normalize_command(line: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    for command in FREE_TEXT_COMMANDS {
+        let command_words = command.split(' ').count();
+        if words.len() > command_words && words[..command_words].join(" ") == *command {
+            let mut rest = line.trim();
+            for _ in 0..command_words {
+                rest = rest
+                    .trim_start()
+                    .trim_start_matches(|c: char| !c.is_whitespace());
+            }
+            return format!("{} {}", command, rest.trim_start());
+        }
+    }
+    words.join(" ")
+}
+
+/// Runs one command line, the error says why an unknown or failed command didn't run.
+async fn handle_command(line: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    if let Some(action) = swarm.behaviour_mut().confirmation.take() {
+        return handle_confirmation(action, line, swarm).await;
+    }
+    let line = normalize_command(line);
+    match line.as_str() {
+        "list peers" => handle_list_peers(swarm).await,
+        "list peers json" => handle_list_peers_json(swarm),
+        cmd if is_command(cmd, "list songs") => handle_list_songs(cmd, swarm).await,
+        cmd if is_command(cmd, "create song") => handle_create_song(cmd).await,
+        cmd if is_command(cmd, "publish song") => handle_publish_song(cmd, swarm).await,
+        "announce" => handle_announce(swarm).await,
+        "help" => {
+            print_help();
+            Ok(())
+        }
+        "ping all" => handle_ping_all(swarm),
+        cmd if is_command(cmd, "whoami") => handle_whoami(cmd, swarm).await,
+        cmd if is_command(cmd, "set nick") => handle_set_nick(cmd).await,
+        cmd if is_command(cmd, "validate") => handle_validate(cmd).await,
+        cmd if is_command(cmd, "share song") => handle_share_song(cmd).await,
+        cmd if is_command(cmd, "stats") => handle_stats(cmd).await,
+        cmd if is_command(cmd, "clone peer") => handle_clone_peer(cmd, swarm),
+        cmd if is_command(cmd, "show song") => handle_show_song(cmd, swarm).await,
+        cmd if is_command(cmd, "seed") => handle_seed_peer(cmd, swarm),
+        "normalize explicit" => handle_normalize_explicit().await,
+        cmd if is_command(cmd, "respond auto") => handle_respond_auto(cmd, swarm),
+        "compare all" => start_query(swarm, ListMode::ALL, QueryKind::Compare),
+        cmd if is_command(cmd, "publish artist") || is_command(cmd, "private artist") => {
+            handle_artist_visibility(cmd, swarm).await
+        }
+        "recent" => handle_recent(swarm),
+        "artists" => handle_artists().await,
+        cmd if is_command(cmd, "raw song") => handle_raw_song(cmd).await,
+        cmd if is_command(cmd, "note") => handle_note(cmd).await,
+        cmd if is_command(cmd, "netstat") => handle_netstat(cmd, swarm),
+        cmd if is_command(cmd, "set default explicit") => handle_set_default_explicit(cmd).await,
+        cmd if is_command(cmd, "search songs") => handle_search_songs(cmd).await,
+        cmd if is_command(cmd, "topic") => handle_topic(cmd, swarm),
+        cmd if is_command(cmd, "export lyrics") => handle_export_lyrics(cmd).await,
+        "reset identity" => handle_reset_identity(swarm),
+        cmd if is_command(cmd, "display") => handle_display(cmd).await,
+        cmd if is_command(cmd, "verify peer") => handle_verify_peer(cmd, swarm),
+        cmd if is_command(cmd, "preview share") => handle_preview_share(cmd, swarm).await,
+        "edit all" => handle_edit_all(swarm),
+        "queries" => handle_queries(swarm),
+        "list received" => handle_list_received(swarm).await,
+        cmd if is_command(cmd, "save received") => handle_save_received(cmd, swarm).await,
+        "clear received" => {
+            swarm.behaviour_mut().received.clear();
+            info!("Cleared received songs");
+            Ok(())
+        }
+        "list pins" => handle_list_pins(swarm).await,
+        cmd if is_command(cmd, "pin") => handle_pin(cmd, swarm).await,
+        cmd if is_command(cmd, "unpin") => handle_unpin(cmd, swarm).await,
+        cmd if is_command(cmd, "export peers") => handle_export_peers(cmd, swarm).await,
+        cmd if is_command(cmd, "import peers") => handle_import_peers(cmd, swarm).await,
+        cmd if is_command(cmd, "catalog only") => handle_catalog_only(cmd, swarm),
+        "selftest" => handle_selftest().await,
+        cmd if is_command(cmd, "snapshot") => handle_snapshot(cmd).await,
+        cmd if is_command(cmd, "follow") => handle_follow(cmd, swarm),
+        cmd if is_command(cmd, "unfollow") => handle_unfollow(cmd, swarm),
+        "census" => handle_census(swarm),
+        "find duplicates" => handle_find_duplicates().await,
+        cmd if is_command(cmd, "merge duplicates") => handle_merge_duplicates(cmd, swarm).await,
+        _ => Err("unknown command".into()),
+    }
+}
+
+/// Whether `cmd` is `name`, alone or followed by arguments, so `statsfoo` isn't `stats`.
+fn is_command(cmd: &str, name: &str) -> bool {
+    cmd.strip_prefix(name)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+}
+
+/// Command usage and description, printed by `help` and at startup.
+const COMMANDS: &[(&str, &str)] = &[
+    ("list peers", "list discovered peers"),
+    ("list songs [sort title|artist]", "list local songs"),
+    (
+        "list songs all [sort title|artist]",
+        "list public songs of all peers",
+    ),
+    (
+        "list songs <peer id> [sort title|artist]",
+        "list public songs of one peer",
+    ),
+    (
+        "list songs since <duration> [sort title|artist]",
+        "list songs changed recently, e.g. 2d",
+    ),
+    (
+        "create song <title>|<artist>|<lyrics>[|<explicit>]",
+        "create a private song",
+    ),
+    ("publish song <id>", "make a song public"),
+    (
+        "publish song <id> to <peer id>...",
+        "make a song visible to the given peers only",
+    ),
+    ("announce", "tell peers your library changed"),
+    ("set nick <name>", "set the nickname shown to peers"),
+    (
+        "whoami [--json]",
+        "show your peer id, nickname and node status",
+    ),
+    ("validate [--fix]", "check the library for problems"),
+    (
+        "share song <id> [path]",
+        "print a song as text, optionally to a file",
+    ),
+    (
+        "stats [export <path>]",
+        "show library statistics or save them as JSON",
+    ),
+    (
+        "clone peer <peer id>",
+        "copy a peer's public songs into your library",
+    ),
+    ("show song <id>", "show all details of a song"),
+    (
+        "seed <peer id>",
+        "re-share a peer's public songs from your library",
+    ),
+    (
+        "ping all",
+        "show the latest latency to every connected peer",
+    ),
+    (
+        "normalize explicit",
+        "rewrite explicit values as true/false",
+    ),
+    ("quit", "print a session summary and exit"),
+    ("respond auto on|off", "answer or ignore list all requests"),
+    ("compare all", "show your public songs no other peer shares"),
+    ("list peers json", "list discovered peers as JSON"),
+    (
+        "publish artist <name>",
+        "make all songs by an artist public",
+    ),
+    (
+        "private artist <name>",
+        "make all songs by an artist private",
+    ),
+    ("recent", "list songs shown this session, newest first"),
+    ("artists", "list distinct artists with song counts"),
+    ("raw song <id>", "print a song as stored, in JSON"),
+    (
+        "note <id> <text>|--clear",
+        "add to or clear a song's private notes",
+    ),
+    ("netstat [--json]", "show network diagnostics"),
+    (
+        "set default explicit yes|no",
+        "explicit value for songs created without one",
+    ),
+    (
+        "search songs <query> [--lyrics]",
+        "find local songs by title or artist",
+    ),
+    ("topic <name>", "leave the current topics and join another"),
+    (
+        "export lyrics <dir>",
+        "write each song's lyrics to a text file",
+    ),
+    (
+        "reset identity",
+        "replace your keypair (needs --persist-identity)",
+    ),
+    (
+        "list songs missing <field> [sort title|artist]",
+        "list songs with an empty title, artist, lyrics, notes, platform, cover or added_by",
+    ),
+    (
+        "list songs random [<n>] [--seed <number>] [sort title|artist]",
+        "list n random local songs (default 5)",
+    ),
+    (
+        "list songs platform <name>",
+        "list songs collected from a platform, like local or peer",
+    ),
+    (
+        "display compact|verbose",
+        "one line per song, or every field",
+    ),
+    (
+        "verify peer <peer id>",
+        "check a peer's floodsub identity against its connection",
+    ),
+    (
+        "preview share [<peer id>]",
+        "print the songs a list request would get right now",
+    ),
+    ("edit all", "edit the whole library as JSON in $EDITOR"),
+    ("queries", "list queries still waiting for responses"),
+    (
+        "list received",
+        "list songs from the last query that aren't in your library",
+    ),
+    (
+        "save received <n>",
+        "save song #n of list received as a private song",
+    ),
+    ("clear received", "forget the songs received so far"),
+    (
+        "pin <peer id or multiaddr>",
+        "dial a peer now and at every start",
+    ),
+    (
+        "unpin <peer id or multiaddr>",
+        "stop dialing a pinned peer at start",
+    ),
+    (
+        "list pins",
+        "list pinned peers and whether they are connected",
+    ),
+    (
+        "follow [peer id]",
+        "notify of new songs from a peer every --follow-interval, or list followed peers",
+    ),
+    ("unfollow <peer id>", "stop following a peer"),
+    (
+        "export peers <path>",
+        "write this node and known peers with addresses as JSON",
+    ),
+    (
+        "import peers <path>",
+        "dial the peers of an export peers file",
+    ),
+    (
+        "catalog only on|off",
+        "leave lyrics out of answers to list all requests",
+    ),
+    (
+        "selftest",
+        "check the library and messages survive serialization",
+    ),
+    (
+        "snapshot [list | diff <a> <b>]",
+        "save, list or compare copies of the library",
+    ),
+    (
+        "census",
+        "ask every peer for its nickname, version and public song count",
+    ),
+    (
+        "find duplicates",
+        "list groups of songs with near-identical titles by the same artist",
+    ),
+    (
+        "merge duplicates <group>",
+        "merge a group from find duplicates into its first song",
+    ),
+    ("help", "show this menu"),
+];
+
+fn print_banner() {
+    info!(r"  ___ _ __   ___ | |_(_)/ _|_   _     | |__ | | ___   __ _ ");
+    info!(r" / __| '_ \ / _ \| __| | |_| | | |____| '_ \| |/ _ \ / _` |");
+    info!(r" \__ \ |_) | (_) | |_| |  _| |_| |____| |_) | | (_) | (_| |");
+    info!(r" |___/ .__/ \___/ \__|_|_|  \__, |    |_.__/|_|\___/ \__, |");
+    info!(r"     |_|                    |___/                    |___/ ");
+}
+
+/// Prints `--prompt` on stderr, next to the log output it follows. Skipped with `--quiet` and
+/// when stdin isn't a terminal, where nobody reads it.
+fn show_prompt() {
+    if CONFIG.quiet || CONFIG.prompt.is_empty() || !std::io::stdin().is_terminal() {
+        return;
+    }
+    let mut stderr = std::io::stderr();
+    let _ = write!(stderr, "{}", CONFIG.prompt);
+    let _ = stderr.flush();
+}
+
+fn print_help() {
+    info!("Commands:");
+    COMMANDS
+        .iter()
+        .for_each(|(usage, about)| info!("  {:<50} {}", usage, about));
+}
+
+/// Sends an mdns query right away. `Mdns` has no method for it, but a new listen address
+/// restarts its query timer immediately, after which it keeps the default interval.
+fn query_mdns_now(swarm: &mut Swarm<SongBehaviour>, listener: ListenerId, addr: &Multiaddr) {
+    swarm
+        .behaviour_mut()
+        .mdns
+        .inject_new_listen_addr(listener, addr);
+}
+
+/// Prints a progress line per startup round and a summary after the last one.
+fn report_discovery_progress(swarm: &Swarm<SongBehaviour>, round: u32) {
+    let found = swarm
+        .behaviour()
+        .mdns
+        .discovered_nodes()
+        .collect::<HashSet<_>>()
+        .len();
+    info!(
+        "Discovering peers... ({}/{}, {} found)",
+        round, CONFIG.discovery_retries, found
+    );
+    if round == CONFIG.discovery_retries {
+        if found == 0 {
+            info!("No peers yet, they will be added as they are discovered");
+        } else {
+            info!("Discovered {} peers on the local network", found);
+        }
+    }
+}
+
+async fn handle_list_peers(swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    info!("Discovered Peers:");
+    let nodes = swarm.behaviour().mdns.discovered_nodes();
+    let mut unique_peers = HashSet::new();
+    for peer in nodes {
+        unique_peers.insert(peer);
+    }
+    if unique_peers.is_empty() {
+        info!("No peers discovered yet. Make sure you're on the same network.");
+        return Ok(());
+    }
+    let nicknames = &swarm.behaviour().nicknames;
+    unique_peers
+        .iter()
+        .for_each(|p| match nicknames.get(&p.to_string()) {
+            Some(nick) => info!("{} ({})", p, nick),
+            None => info!("{}", p),
+        });
+    Ok(())
+}
+
+fn handle_list_peers_json(swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let peers: HashSet<PeerId> = swarm.behaviour().mdns.discovered_nodes().copied().collect();
+    let statuses: Vec<PeerStatus> = peers
+        .into_iter()
+        .map(|peer| PeerStatus {
+            peer_id: peer.to_string(),
+            nickname: swarm.behaviour().nicknames.get(&peer.to_string()).cloned(),
+            addresses: swarm
+                .behaviour_mut()
+                .mdns
+                .addresses_of_peer(&peer)
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+            last_seen: swarm.behaviour().last_seen.get(&peer).copied(),
+            connected: swarm.is_connected(&peer),
+        })
+        .collect();
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&statuses).expect("can jsonify peers")
+    );
+    Ok(())
+}
+
+/// Writes this node and every known peer with an address, for others to `import peers`.
+async fn handle_export_peers(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let path = cmd.strip_prefix("export peers").unwrap_or_default().trim();
+    if path.is_empty() {
+        return Err("usage: export peers <path>".into());
+    }
+    let mut records = vec![PeerRecord {
+        peer_id: PEER_ID.to_string(),
+        addresses: swarm.listeners().map(|a| a.to_string()).collect(),
+    }];
+    let behaviour = swarm.behaviour_mut();
+    let mut peers: Vec<PeerId> = behaviour
+        .mdns
+        .discovered_nodes()
+        .chain(behaviour.partial_view.iter())
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    peers.sort();
+    for peer in peers {
+        let addresses: Vec<String> = behaviour
+            .addresses_of_peer(&peer)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        if !addresses.is_empty() {
+            records.push(PeerRecord {
+                peer_id: peer.to_string(),
+                addresses,
+            });
+        }
+    }
+    let json = serde_json::to_string_pretty(&records).expect("can jsonify peers");
+    match fs::write(path, json).await {
+        Ok(()) => info!(
+            "Exported {} peer(s) including this node to {}",
+            records.len(),
+            path
+        ),
+        Err(e) => return Err(format!("can't write {}: {}", path, e).into()),
+    }
+    Ok(())
+}
+
+/// Dials the peers of an `export peers` file and adds them to the floodsub partial view.
+async fn handle_import_peers(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let path = cmd.strip_prefix("import peers").unwrap_or_default().trim();
+    if path.is_empty() {
+        return Err("usage: import peers <path>".into());
+    }
+    let records: Vec<PeerRecord> = match fs::read(path).await {
+        Ok(content) => match serde_json::from_slice(&content) {
+            Ok(records) => records,
+            Err(e) => {
+                return Err(format!("{} isn't a peer list: {}", path, e).into());
+            }
+        },
+        Err(e) => {
+            return Err(format!("can't read {}: {}", path, e).into());
+        }
+    };
+    let mut dialed = 0;
+    let mut skipped = 0;
+    for record in records {
+        let peer = match PeerId::from_str(&record.peer_id) {
+            Ok(peer) if peer == *PEER_ID => continue,
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("skipping invalid peer id {}: {}", record.peer_id, e);
+                skipped += 1;
+                continue;
+            }
+        };
+        let addresses: Vec<Multiaddr> = record
+            .addresses
+            .iter()
+            .filter_map(|a| match peer_address(a, peer) {
+                Ok(addr) => Some(addr),
+                Err(e) => {
+                    warn!("skipping address {} of {}: {}", a, peer, e);
+                    None
+                }
+            })
+            .collect();
+        if addresses.is_empty() {
+            skipped += 1;
+            continue;
+        }
+        let behaviour = swarm.behaviour_mut();
+        behaviour.floodsub.add_node_to_partial_view(peer);
+        behaviour.partial_view.insert(peer);
+        if swarm.is_connected(&peer) || addresses.into_iter().any(|a| swarm.dial_addr(a).is_ok()) {
+            dialed += 1;
+        } else {
+            warn!("can't dial any address of {}", peer);
+            skipped += 1;
+        }
+    }
+    info!(
+        "Dialing {} peer(s) from {}, skipped {}",
+        dialed, path, skipped
+    );
+    Ok(())
+}
+
+/// Validates an imported address and makes sure it ends in `/p2p/<peer>`.
+fn peer_address(addr: &str, peer: PeerId) -> std::result::Result<Multiaddr, String> {
+    let mut addr: Multiaddr = addr
+        .parse()
+        .map_err(|e| format!("invalid multiaddr: {}", e))?;
+    match addr.iter().last() {
+        Some(Protocol::P2p(hash)) if hash == *peer.as_ref() => Ok(addr),
+        Some(Protocol::P2p(_)) => Err("ends with a different peer id".to_owned()),
+        _ => {
+            addr.push(Protocol::P2p(peer.into()));
+            Ok(addr)
+        }
+    }
+}
+
+/// Serializes, parses and serializes again, the two encodings must be identical.
+fn check_round_trip<T: Serialize + DeserializeOwned>(value: &T) -> std::result::Result<(), String> {
+    let json = serde_json::to_string(value).map_err(|e| format!("doesn't serialize: {}", e))?;
+    let parsed: T =
+        serde_json::from_str(&json).map_err(|e| format!("doesn't parse back: {}", e))?;
+    let again = serde_json::to_string(&parsed).map_err(|e| e.to_string())?;
+    if again != json {
+        return Err("changes after a round trip".to_owned());
+    }
+    Ok(())
+}
+
+/// Round trips a message through JSON and checks it is taken for the right type on receipt.
+fn check_message<T: Serialize + DeserializeOwned>(
+    kind: &str,
+    value: &T,
+) -> std::result::Result<(), String> {
+    check_round_trip(value)?;
+    let data = serde_json::to_vec(value).expect("can jsonify message");
+    match parse_message(&data).as_ref().map(Message::kind) {
+        Some(received) if received == kind => Ok(()),
+        Some(received) => Err(format!("is received as a {}", received)),
+        None => Err("isn't recognized on receipt".to_owned()),
+    }
+}
+
+async fn handle_selftest() -> Result<()> {
+    let songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let mut results: Vec<(String, std::result::Result<(), String>)> = Vec::new();
+    results.push(("library".to_owned(), check_round_trip(&songs)));
+    for song in &songs {
+        results.push((format!("song {}", song.id), check_round_trip(song)));
+    }
+    match read_settings().await {
+        Ok(settings) => results.push(("settings".to_owned(), check_round_trip(&settings))),
+        Err(e) => results.push(("settings".to_owned(), Err(e.to_string()))),
+    }
+
+    let mut resp = ListResponse {
+        mode: ListMode::ALL,
+        data: shared_songs(songs.clone(), "", false),
+        receiver: PEER_ID.to_string(),
+        public_key: Vec::new(),
+        signature: Vec::new(),
+        compress: true,
+    };
+    resp.sign();
+    results.push((
+        "ListResponse".to_owned(),
+        check_message("ListResponse", &resp),
+    ));
+    // the way it travels: gzipped if large, inflated and checked against its signature
+    let wire = encode_response(&resp);
+    let received = inflate_message(&wire)
+        .map_err(|e| format!("doesn't decompress: {}", e))
+        .and_then(|data| {
+            serde_json::from_slice::<ListResponse>(&data)
+                .map_err(|e| format!("doesn't parse: {}", e))
+        })
+        .and_then(|parsed| {
+            if !parsed.verify(&PEER_ID) {
+                Err("signature doesn't verify after transport".to_owned())
+            } else if serde_json::to_string(&parsed.data).ok()
+                != serde_json::to_string(&resp.data).ok()
+            {
+                Err("songs change in transport".to_owned())
+            } else {
+                Ok(())
+            }
+        });
+    results.push(("ListResponse on the wire".to_owned(), received));
+    let request = ListRequest {
+        mode: ListMode::One(PEER_ID.to_string()),
+        accept_gzip: true,
+    };
+    results.push((
+        "ListRequest".to_owned(),
+        check_message("ListRequest", &request),
+    ));
+    let update = LibraryUpdated {
+        source: PEER_ID.to_string(),
+        nick: Some("selftest".to_owned()),
+    };
+    results.push((
+        "LibraryUpdated".to_owned(),
+        check_message("LibraryUpdated", &update),
+    ));
+    let unpublish = Unpublish {
+        source: PEER_ID.to_string(),
+        id: songs.first().map(|s| s.id).unwrap_or_default(),
+    };
+    results.push((
+        "Unpublish".to_owned(),
+        check_message("Unpublish", &unpublish),
+    ));
+    let census = CensusRequest {
+        requester: PEER_ID.to_string(),
+    };
+    results.push((
+        "CensusRequest".to_owned(),
+        check_message("CensusRequest", &census),
+    ));
+    let reply = CensusReply {
+        receiver: PEER_ID.to_string(),
+        nick: Some("selftest".to_owned()),
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        public_songs: songs.iter().filter(|s| s.public).count(),
+    };
+    results.push((
+        "CensusReply".to_owned(),
+        check_message("CensusReply", &reply),
+    ));
+
+    let failed: Vec<_> = results.iter().filter(|(_, r)| r.is_err()).collect();
+    for (name, result) in &failed {
+        if let Err(e) = result {
+            error!("FAIL {}: {}", name, e);
+        }
+    }
+    if wire.len() > FLOODSUB_MAX_PACKET {
+        warn!(
+            "A full response is {} bytes on the wire, over the floodsub limit of {} bytes",
+            wire.len(),
+            FLOODSUB_MAX_PACKET
+        );
+    }
+    if !failed.is_empty() {
+        return Err(format!(
+            "Selftest failed: {} of {} checks",
+            failed.len(),
+            results.len()
+        )
+        .into());
+    }
+    info!("Selftest passed: {} checks", results.len());
+    Ok(())
+}
+
+fn snapshot_dir() -> PathBuf {
+    CONFIG.data_dir.join(SNAPSHOT_DIR_NAME)
+}
+
+/// Snapshots are named after the unix time they were taken, `current` is the live library.
+async fn read_snapshot(name: &str) -> Result<Songs> {
+    if name == "current" {
+        return read_local_songs().await;
+    }
+    if name.parse::<u64>().is_err() {
+        return Err(format!("unknown snapshot {:?}, see 'snapshot list'", name).into());
+    }
+    let path = snapshot_dir().join(format!("{}.json", name));
+    match fs::read(&path).await {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            Err(format!("no snapshot {}, see 'snapshot list'", name).into())
+        }
+        Err(e) => Err(format!("can't read {}: {}", path.display(), e).into()),
+    }
+}
+
+async fn list_snapshots() -> Result<Vec<u64>> {
+    let mut names = Vec::new();
+    let mut entries = match fs::read_dir(snapshot_dir()).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(time) = name.strip_suffix(".json").and_then(|n| n.parse().ok()) {
+            names.push(time);
+        }
+    }
+    names.sort_unstable();
+    Ok(names)
+}
+
+async fn handle_snapshot(cmd: &str) -> Result<()> {
+    let args: Vec<&str> = cmd.split_whitespace().skip(1).collect();
+    match args.as_slice() {
+        [] => take_snapshot().await,
+        ["list"] => {
+            let names = list_snapshots()
+                .await
+                .map_err(|e| format!("error listing snapshots: {}", e))?;
+            if names.is_empty() {
+                info!("No snapshots yet, take one with 'snapshot'");
+            } else {
+                info!("Snapshots ({}):", names.len());
+                names.iter().for_each(|n| info!("  {}", n));
+            }
+            Ok(())
+        }
+        ["diff", a, b] => diff_snapshots(a, b).await,
+        _ => Err("usage: snapshot | snapshot list | snapshot diff <a> <b>".into()),
+    }
+}
+
+async fn take_snapshot() -> Result<()> {
+    let songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let dir = snapshot_dir();
+    if let Err(e) = fs::create_dir_all(&dir).await {
+        return Err(format!("can't create {}: {}", dir.display(), e).into());
+    }
+    // two snapshots within a second would share a name, the later one waits for the next
+    let mut name = now_secs();
+    while fs::metadata(dir.join(format!("{}.json", name)))
+        .await
+        .is_ok()
+    {
+        name += 1;
+    }
+    let json = serde_json::to_string(&songs).expect("can jsonify songs");
+    fs::write(dir.join(format!("{}.json", name)), json)
+        .await
+        .map_err(|e| format!("error saving snapshot: {}", e))?;
+    info!("Saved snapshot {} with {} songs", name, songs.len());
+    Ok(())
+}
+
+/// Names the fields of a song that differ between two versions of it.
+fn changed_fields(a: &Song, b: &Song) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    // the content hash covers title, artist, lyrics and explicit
+    if content_checksum(a) != content_checksum(b) {
+        for field in ["title", "artist", "lyrics"] {
+            if text_field(a, field) != text_field(b, field) {
+                fields.push(field);
+            }
+        }
+        if a.explicit != b.explicit {
+            fields.push("explicit");
+        }
+    }
+    if a.public != b.public {
+        fields.push("public");
+    }
+    if a.notes != b.notes {
+        fields.push("notes");
+    }
+    if a.shared_with != b.shared_with {
+        fields.push("shared with");
+    }
+    if a.cover != b.cover {
+        fields.push("cover");
+    }
+    fields
+}
+
+async fn diff_snapshots(a: &str, b: &str) -> Result<()> {
+    let (old, new) = match (read_snapshot(a).await, read_snapshot(b).await) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+    let old_by_id: HashMap<usize, &Song> = old.iter().map(|s| (s.id, s)).collect();
+    let new_ids: HashSet<usize> = new.iter().map(|s| s.id).collect();
+    let label = |s: &Song| format!("{}: {} - {}", s.id, s.title.trim(), s.artist.trim());
+    let mut changes = 0;
+    info!("Changes from {} to {}:", a, b);
+    for song in &new {
+        match old_by_id.get(&song.id) {
+            None => info!("  + {}", label(song)),
+            Some(before) => {
+                let fields = changed_fields(before, song);
+                if fields.is_empty() {
+                    continue;
+                }
+                info!("  ~ {} ({})", label(song), fields.join(", "));
+            }
+        }
+        changes += 1;
+    }
+    for song in old.iter().filter(|s| !new_ids.contains(&s.id)) {
+        info!("  - {}", label(song));
+        changes += 1;
+    }
+    if changes == 0 {
+        info!("  no changes");
+    }
+    Ok(())
+}
+
+/// Parses a song id argument, naming the offending token when it isn't one.
+fn parse_song_id(arg: &str) -> std::result::Result<usize, String> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        return Err("missing song id".to_owned());
+    }
+    arg.parse()
+        .map_err(|_| format!("'{}' is not a valid song id", arg))
+}
+
+/// Splits a trailing `sort <key>` off listing arguments.
+fn parse_sort_option(args: &str) -> std::result::Result<(String, Option<SortKey>), String> {
+    let tokens: Vec<&str> = args.split_whitespace().collect();
+    match tokens.iter().position(|t| *t == "sort") {
+        Some(pos) if pos + 2 == tokens.len() => {
+            Ok((tokens[..pos].join(" "), Some(tokens[pos + 1].parse()?)))
+        }
+        Some(_) => Err("expected: sort title|artist".to_owned()),
+        None => Ok((tokens.join(" "), None)),
+    }
+}
+
+async fn handle_list_songs(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let rest = cmd.strip_prefix("list songs").unwrap_or_default();
+    let (target, sort) = parse_sort_option(rest)?;
+    match target.as_str() {
+        "all" => start_query(swarm, ListMode::ALL, QueryKind::List(sort)),
+        since if since.starts_with("since ") => {
+            list_songs_since(since.trim_start_matches("since "), sort).await
+        }
+        missing if missing.starts_with("missing ") => {
+            list_songs_missing(missing.trim_start_matches("missing "), sort).await
+        }
+        random if random == "random" || random.starts_with("random ") => {
+            list_songs_random(random.trim_start_matches("random"), sort).await
+        }
+        platform if platform.starts_with("platform ") => {
+            list_songs_on_platform(platform.trim_start_matches("platform "), sort).await
+        }
+        "since" => Err("usage: list songs since <duration>, e.g. 2d".into()),
+        "missing" => Err(format!(
+            "usage: list songs missing <field>, one of {}",
+            AUDITABLE_FIELDS.join(", ")
+        )
+        .into()),
+        "platform" => Err("usage: list songs platform <name>".into()),
+        "" => {
+            let mut v = read_local_songs()
+                .await
+                .map_err(|e| format!("error fetching local songs: {}", e))?;
+            if let Some(key) = sort {
+                v.sort_by(|a, b| key.compare(a, b));
+            }
+            info!("Local Songs ({})", v.len());
+            if v.is_empty() {
+                info!("No songs yet. Use 'create song' to add one.");
+            }
+            v.iter().for_each(|r| print_song(r, None));
+            Ok(())
+        }
+        songs_peer_id => match PeerId::from_str(songs_peer_id) {
+            Ok(_) => start_query(
+                swarm,
+                ListMode::One(songs_peer_id.to_owned()),
+                QueryKind::List(sort),
+            ),
+            Err(_) => Err(format!(
+                "unknown list songs option or invalid peer id: {}",
+                songs_peer_id
+            )
+            .into()),
+        },
+    }
+}
+
+const RANDOM_SAMPLE: usize = 5;
+
+/// `[<n>] [--seed <seed>]`, the same seed and library always give the same sample.
+async fn list_songs_random(args: &str, sort: Option<SortKey>) -> Result<()> {
+    let mut count = RANDOM_SAMPLE;
+    let mut seed = None;
+    let mut tokens = args.split_whitespace();
+    while let Some(token) = tokens.next() {
+        let valid = if token == "--seed" {
+            seed = tokens.next().and_then(|s| s.parse().ok());
+            seed.is_some()
+        } else if let Ok(n) = token.parse() {
+            count = n;
+            true
+        } else {
+            false
+        };
+        if !valid {
+            return Err("usage: list songs random [<n>] [--seed <number>]".into());
+        }
+    }
+    let songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut sample: Vec<&Song> = songs.choose_multiple(&mut rng, count).collect();
+    if let Some(key) = sort {
+        sample.sort_by(|a, b| key.compare(a, b));
+    }
+    info!("Random songs ({} of {})", sample.len(), songs.len());
+    if songs.is_empty() {
+        info!("No songs yet. Use 'create song' to add one.");
+    }
+    sample.iter().for_each(|r| print_song(r, None));
+    Ok(())
+}
+
+async fn list_songs_on_platform(platform: &str, sort: Option<SortKey>) -> Result<()> {
+    let platform = platform.trim().to_lowercase();
+    let mut songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    songs.retain(|s| s.platform.as_deref().map(str::to_lowercase) == Some(platform.clone()));
+    if let Some(key) = sort {
+        songs.sort_by(|a, b| key.compare(a, b));
+    }
+    info!("Songs from {} ({})", platform, songs.len());
+    songs.iter().for_each(|r| print_song(r, None));
+    Ok(())
+}
+
+/// Text fields `list songs missing` can check.
+const AUDITABLE_FIELDS: &[&str] = &[
+    "title", "artist", "lyrics", "notes", "platform", "cover", "added_by",
+];
+
+fn text_field<'a>(song: &'a Song, field: &str) -> Option<&'a str> {
+    match field {
+        "title" => Some(&song.title),
+        "artist" => Some(&song.artist),
+        "lyrics" => Some(&song.lyrics),
+        "notes" => Some(&song.notes),
+        "platform" => Some(song.platform.as_deref().unwrap_or_default()),
+        "cover" => Some(song.cover.as_deref().unwrap_or_default()),
+        "added_by" => Some(song.added_by.as_deref().unwrap_or_default()),
+        _ => None,
+    }
+}
+
+async fn list_songs_missing(field: &str, sort: Option<SortKey>) -> Result<()> {
+    if !AUDITABLE_FIELDS.contains(&field) {
+        return Err(format!(
+            "unknown field: {}, expected one of {}",
+            field,
+            AUDITABLE_FIELDS.join(", ")
+        )
+        .into());
+    }
+    let songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let mut missing: Vec<&Song> = songs
+        .iter()
+        .filter(|s| text_field(s, field).is_some_and(|v| v.trim().is_empty()))
+        .collect();
+    if let Some(key) = sort {
+        missing.sort_by(|a, b| key.compare(a, b));
+    }
+    info!("Songs missing {} ({})", field, missing.len());
+    if missing.is_empty() {
+        info!("Every song has {} filled in", field);
+    }
+    missing.iter().for_each(|r| print_song(r, None));
+    Ok(())
+}
+
+/// Newest first, unless a sort key is given.
+async fn list_songs_since(window_arg: &str, sort: Option<SortKey>) -> Result<()> {
+    let window = parse_duration(window_arg)?;
+    let songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let cutoff = now_secs().saturating_sub(window.as_secs());
+    let legacy = songs.iter().filter(|s| s.updated_at.is_none()).count();
+    let mut recent: Vec<&Song> = songs
+        .iter()
+        .filter(|s| s.updated_at.is_some_and(|t| t >= cutoff))
+        .collect();
+    match sort {
+        Some(key) => recent.sort_by(|a, b| key.compare(a, b)),
+        None => recent.sort_by_key(|s| std::cmp::Reverse(s.updated_at)),
+    }
+    info!(
+        "Songs changed in the last {} ({})",
+        window_arg,
+        recent.len()
+    );
+    if recent.is_empty() {
+        info!("No songs changed in that time");
+    }
+    recent.iter().for_each(|r| print_song(r, None));
+    if legacy > 0 {
+        info!("{} song(s) without a timestamp were skipped", legacy);
+    }
+    Ok(())
+}
+
+fn start_query(swarm: &mut Swarm<SongBehaviour>, mode: ListMode, kind: QueryKind) -> Result<()> {
+    swarm.behaviour_mut().start_query(mode, kind)
+}
+
+async fn handle_create_song(cmd: &str) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("create song") {
+        let elements: Vec<&str> = rest.split("|").collect();
+        let default_explicit = default_explicit().await;
+        if elements.len() < 3 {
+            return Err(format!(
+                "too few arguments - Format: title|artist|lyrics|explicit|cover (explicit defaults to {})",
+                default_explicit
+            )
+            .into());
+        } else {
+            let title = elements.first().expect("title is there");
+            let artist = elements.get(1).expect("artist is there");
+            let lyrics = elements.get(2).expect("lyrics are there");
+            let explicit = explicit_field(elements.get(3).copied(), default_explicit)?;
+            let cover = elements.get(4).map(|c| c.trim()).filter(|c| !c.is_empty());
+            if let Err(e) = create_new_song(title, artist, lyrics, explicit, cover).await {
+                return Err(format!("error creating song: {}", e).into());
+            };
+        }
+    }
+    Ok(())
+}
+
+async fn handle_publish_song(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("publish song") {
+        let (id_arg, peers) = match rest.split_once(" to ") {
+            Some((id_arg, peers)) => {
+                let peers: Vec<String> = peers.split_whitespace().map(str::to_owned).collect();
+                if let Some(bad) = peers.iter().find(|p| PeerId::from_str(p).is_err()) {
+                    return Err(format!("invalid peer id: {}", bad).into());
+                }
+                (id_arg, Some(peers))
+            }
+            None => (rest, None),
+        };
+        let id = parse_song_id(id_arg)?;
+        match publish_song(id, peers.clone()).await {
+            Ok(true) => {
+                match &peers {
+                    Some(peers) => info!(
+                        "Published Song with id: {} to {} peer(s) only",
+                        id,
+                        peers.len()
+                    ),
+                    None => info!("Published Song with id: {}", id),
+                }
+                if CONFIG.auto_announce {
+                    handle_announce(swarm).await?;
+                }
+            }
+            Ok(false) => return Err(format!("Song with id {} not found", id).into()),
+            Err(e) => return Err(format!("error publishing song with id {}, {}", id, e).into()),
+        };
+    }
+    Ok(())
+}
+
+async fn handle_artist_visibility(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let (artist, public) = if let Some(rest) = cmd.strip_prefix("publish artist") {
+        (rest.trim(), true)
+    } else if let Some(rest) = cmd.strip_prefix("private artist") {
+        (rest.trim(), false)
+    } else {
+        return Ok(());
+    };
+    if artist.is_empty() {
+        return Err("usage: publish artist <name> | private artist <name>".into());
+    }
+    let visibility = if public { "public" } else { "private" };
+    match set_artist_public(artist, public).await {
+        Ok((0, _)) => return Err(format!("No songs by {} found", artist).into()),
+        Ok((matched, changed)) => {
+            info!(
+                "Made {} song(s) by {} {} ({} already were)",
+                changed.len(),
+                artist,
+                visibility,
+                matched - changed.len()
+            );
+            if public && !changed.is_empty() && CONFIG.auto_announce {
+                handle_announce(swarm).await?;
+            }
+            if !public && CONFIG.announce_unpublish {
+                for id in changed {
+                    broadcast_unpublish(swarm, id);
+                }
+            }
+        }
+        Err(e) => return Err(format!("error updating songs by {}: {}", artist, e).into()),
+    }
+    Ok(())
+}
+
+/// Tells peers to drop cached copies of a song that is no longer public.
+fn broadcast_unpublish(swarm: &mut Swarm<SongBehaviour>, id: usize) {
+    let unpublish = Unpublish {
+        source: PEER_ID.to_string(),
+        id,
+    };
+    let json = serde_json::to_string(&unpublish).expect("can jsonify unpublish");
+    swarm.behaviour_mut().publish(json.as_bytes());
+    info!("Asked peers to drop song {}", id);
+}
+
+async fn handle_announce(swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let nick = match read_settings().await {
+        Ok(settings) => settings.nick,
+        Err(e) => {
+            warn!("error reading settings, announcing without nickname: {}", e);
+            None
+        }
+    };
+    let update = LibraryUpdated {
+        source: PEER_ID.to_string(),
+        nick,
+    };
+    let json = serde_json::to_string(&update).expect("can jsonify announcement");
+    swarm.behaviour_mut().publish(json.as_bytes());
+    info!("Announced library update to peers");
+    Ok(())
+}
+
+async fn node_status(swarm: &Swarm<SongBehaviour>) -> Result<NodeStatus> {
+    let settings = read_settings().await?;
+    let songs = read_local_songs().await?;
+    Ok(NodeStatus {
+        peer_id: PEER_ID.to_string(),
+        nickname: settings.nick,
+        topics: swarm.behaviour().topic_names(),
+        profile: CONFIG.profile.clone(),
+        storage_path: STORAGE_FILE_PATH.display().to_string(),
+        listen_addresses: swarm.listeners().map(|a| a.to_string()).collect(),
+        songs: songs.len(),
+        public_songs: songs.iter().filter(|s| s.public).count(),
+        auto_respond: swarm.behaviour().auto_respond,
+        catalog_only: swarm.behaviour().catalog_only,
+    })
+}
+
+fn net_status(swarm: &Swarm<SongBehaviour>) -> NetStatus {
+    let behaviour = swarm.behaviour();
+    NetStatus {
+        listen_addresses: swarm.listeners().map(|a| a.to_string()).collect(),
+        connected_peers: swarm.network_info().num_peers(),
+        topics: behaviour.topic_names(),
+        partial_view: behaviour.partial_view.len(),
+        mdns_discovered: behaviour
+            .mdns
+            .discovered_nodes()
+            .collect::<HashSet<_>>()
+            .len(),
+        mdns_query_interval_secs: MdnsConfig::default().query_interval.as_secs(),
+        transport: if CONFIG.insecure {
+            "plaintext (insecure)"
+        } else {
+            "noise"
+        },
+    }
+}
+
+fn handle_netstat(cmd: &str, swarm: &Swarm<SongBehaviour>) -> Result<()> {
+    let json = match cmd.strip_prefix("netstat").map(str::trim) {
+        Some("") => false,
+        Some("--json") => true,
+        _ => {
+            return Err("usage: netstat [--json]".into());
+        }
+    };
+    let status = net_status(swarm);
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&status).expect("can jsonify status")
+        );
+        return Ok(());
+    }
+    info!("Listening on: {}", status.listen_addresses.join(", "));
+    info!("Connected peers: {}", status.connected_peers);
+    info!("Topics: {}", status.topics.join(", "));
+    info!("Floodsub partial view: {} peer(s)", status.partial_view);
+    info!(
+        "mdns: {} peer(s) discovered, querying every {}s",
+        status.mdns_discovered, status.mdns_query_interval_secs
+    );
+    info!("Transport: {}", status.transport);
+    Ok(())
+}
+
+/// The explicit value for songs created without one: the saved setting, else the config.
+async fn default_explicit() -> bool {
+    match read_settings().await {
+        Ok(settings) => settings.default_explicit.unwrap_or(CONFIG.default_explicit),
+        Err(e) => {
+            error!("error reading settings: {}", e);
+            CONFIG.default_explicit
+        }
+    }
+}
+
+async fn handle_set_default_explicit(cmd: &str) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("set default explicit") {
+        let explicit = match parse_explicit(rest) {
+            Some(explicit) => explicit,
+            None => {
+                return Err("usage: set default explicit yes|no".into());
+            }
+        };
+        let result = match read_settings().await {
+            Ok(mut settings) => {
+                settings.default_explicit = Some(explicit);
+                write_settings(&settings).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => info!(
+                "Songs created without an explicit value are now explicit: {}",
+                explicit
+            ),
+            Err(e) => return Err(format!("error saving default explicit: {}", e).into()),
+        }
+    }
+    Ok(())
+}
+
+async fn handle_display(cmd: &str) -> Result<()> {
+    let mode = match cmd
+        .strip_prefix("display")
+        .map(str::trim)
+        .map(DisplayMode::from_str)
+    {
+        Some(Ok(mode)) => mode,
+        Some(Err(e)) => return Err(e.into()),
+        None => return Ok(()),
+    };
+    let result = match read_settings().await {
+        Ok(mut settings) => {
+            settings.display = Some(mode);
+            write_settings(&settings).await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => {
+            VERBOSE.store(mode == DisplayMode::Verbose, AtomicOrdering::Relaxed);
+            info!("Song listings are now {:?}", mode);
+        }
+        Err(e) => return Err(format!("error saving display mode: {}", e).into()),
+    }
+    Ok(())
+}
+
+/// A pin is a peer id, or a multiaddr that may end in `/p2p/<peer id>`.
+fn parse_pin(target: &str) -> std::result::Result<(Option<Multiaddr>, Option<PeerId>), String> {
+    if !target.starts_with('/') {
+        let peer = PeerId::from_str(target).map_err(|e| format!("invalid peer id: {}", e))?;
+        return Ok((None, Some(peer)));
+    }
+    let addr: Multiaddr = target
+        .parse()
+        .map_err(|e| format!("invalid multiaddr: {}", e))?;
+    let peer = match addr.iter().last() {
+        Some(Protocol::P2p(hash)) => Some(
+            PeerId::from_multihash(hash).map_err(|_| "invalid peer id in multiaddr".to_owned())?,
+        ),
+        _ => None,
+    };
+    Ok((Some(addr), peer))
+}
+
+/// Connects to a pinned peer and keeps it in the floodsub partial view.
+fn dial_pin(swarm: &mut Swarm<SongBehaviour>, target: &str) {
+    let (addr, peer) = match parse_pin(target) {
+        Ok(pin) => pin,
+        Err(e) => {
+            error!("skipping pin {}: {}", target, e);
+            return;
+        }
+    };
+    if let Some(peer) = peer {
+        let behaviour = swarm.behaviour_mut();
+        behaviour.pinned_peers.insert(peer);
+        behaviour.floodsub.add_node_to_partial_view(peer);
+        behaviour.partial_view.insert(peer);
+    }
+    let dialed = match (addr, peer) {
+        (Some(addr), _) => swarm.dial_addr(addr),
+        (None, Some(peer)) if !swarm.is_connected(&peer) => swarm.dial(&peer),
+        _ => Ok(()),
+    };
+    match dialed {
+        Ok(()) => info!("Dialing pinned peer {}", target),
+        Err(e) => warn!("can't dial pinned peer {} yet: {}", target, e),
+    }
+}
+
+async fn handle_pin(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let target = cmd.strip_prefix("pin").unwrap_or_default().trim();
+    if target.is_empty() {
+        return Err("usage: pin <peer id or multiaddr>".into());
+    }
+    if let Err(e) = parse_pin(target) {
+        return Err(format!("usage: pin <peer id or multiaddr>, {}", e).into());
+    }
+    let result = match read_settings().await {
+        Ok(settings) if settings.pins.iter().any(|p| p == target) => {
+            info!("{} is already pinned", target);
+            return Ok(());
+        }
+        Ok(mut settings) => {
+            settings.pins.push(target.to_owned());
+            write_settings(&settings).await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => dial_pin(swarm, target),
+        Err(e) => return Err(format!("error saving pin: {}", e).into()),
+    }
+    Ok(())
+}
+
+async fn handle_unpin(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let target = cmd.strip_prefix("unpin").unwrap_or_default().trim();
+    if target.is_empty() {
+        return Err("usage: unpin <peer id or multiaddr>".into());
+    }
+    let result = match read_settings().await {
+        Ok(mut settings) => {
+            let before = settings.pins.len();
+            settings.pins.retain(|p| p != target);
+            if settings.pins.len() == before {
+                return Err(format!("{} isn't pinned", target).into());
+            }
+            write_settings(&settings).await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => {
+            // the connection stays, mdns expiry can remove the peer again from now on
+            if let Ok((_, Some(peer))) = parse_pin(target) {
+                swarm.behaviour_mut().pinned_peers.remove(&peer);
+            }
+            info!("Unpinned {}", target);
+        }
+        Err(e) => return Err(format!("error saving pins: {}", e).into()),
+    }
+    Ok(())
+}
+
+async fn handle_list_pins(swarm: &Swarm<SongBehaviour>) -> Result<()> {
+    let pins = match read_settings().await {
+        Ok(settings) => settings.pins,
+        Err(e) => {
+            return Err(format!("error reading settings: {}", e).into());
+        }
+    };
+    info!("Pinned peers ({})", pins.len());
+    for pin in &pins {
+        let state = match parse_pin(pin) {
+            Ok((_, Some(peer))) if swarm.is_connected(&peer) => "connected",
+            Ok((_, Some(_))) => "not connected",
+            _ => "address only",
+        };
+        info!("  {} ({})", pin, state);
+    }
+    Ok(())
+}
+
+async fn handle_set_nick(cmd: &str) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("set nick") {
+        let nick = match validate_nick(rest) {
+            Ok(nick) => nick,
+            Err(e) => {
+                return Err(format!("invalid nickname: {}", e).into());
+            }
+        };
+        let result = match read_settings().await {
+            Ok(mut settings) => {
+                settings.nick = Some(nick.clone());
+                write_settings(&settings).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => info!("Nickname set to: {}", nick),
+            Err(e) => return Err(format!("error saving nickname: {}", e).into()),
+        }
+    }
+    Ok(())
+}
+
+async fn handle_whoami(cmd: &str, swarm: &Swarm<SongBehaviour>) -> Result<()> {
+    let json = match cmd.strip_prefix("whoami").map(str::trim) {
+        Some("") => false,
+        Some("--json") => true,
+        _ => {
+            return Err("usage: whoami [--json]".into());
+        }
+    };
+    let status = match node_status(swarm).await {
+        Ok(status) => status,
+        Err(e) => {
+            return Err(format!("error reading node status: {}", e).into());
+        }
+    };
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&status).expect("can jsonify status")
+        );
+        return Ok(());
+    }
+    info!("Peer Id: {}", status.peer_id);
+    info!(
+        "Nickname: {}",
+        status.nickname.as_deref().unwrap_or("(not set)")
+    );
+    info!("Topics: {}", status.topics.join(", "));
+    info!("Profile: {}", status.profile);
+    info!("Storage: {}", status.storage_path);
+    info!("Listening on: {}", status.listen_addresses.join(", "));
+    info!("Songs: {} ({} public)", status.songs, status.public_songs);
+    info!(
+        "Answering list all requests: {}",
+        if status.auto_respond { "on" } else { "off" }
+    );
+    info!(
+        "Catalog only (no lyrics for list all): {}",
+        if status.catalog_only { "on" } else { "off" }
+    );
+    Ok(())
+}
+
+fn handle_catalog_only(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let catalog_only = match cmd.strip_prefix("catalog only").map(str::trim) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => {
+            return Err("usage: catalog only on|off".into());
+        }
+    };
+    swarm.behaviour_mut().catalog_only = catalog_only;
+    if catalog_only {
+        info!("Answering list all requests without lyrics, requests for this peer still get them");
+    } else {
+        info!("Answering list all requests with lyrics");
+    }
+    Ok(())
+}
+
+fn handle_census(swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let behaviour = swarm.behaviour_mut();
+    if behaviour.census.is_some() {
+        return Err("A census is already in progress.".into());
+    }
+    let req = CensusRequest {
+        requester: PEER_ID.to_string(),
+    };
+    let json = serde_json::to_string(&req).expect("can jsonify census request");
+    behaviour.publish(json.as_bytes());
+    behaviour.census = Some(Census {
+        started: Instant::now(),
+        replies: Vec::new(),
+    });
+    info!(
+        "Waiting {}s for census replies...",
+        CONFIG.response_timeout.as_secs()
+    );
+    Ok(())
+}
+
+async fn handle_find_duplicates() -> Result<()> {
+    let songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let groups = duplicate_groups(&songs);
+    if groups.is_empty() {
+        info!("No near-duplicate songs found");
+        return Ok(());
+    }
+    info!("Found {} group(s) of near-duplicates:", groups.len());
+    for (n, group) in groups.iter().enumerate() {
+        info!("Group {}:", n + 1);
+        group.iter().for_each(|s| print_song(s, None));
+    }
+    info!("Merge a group into its first song with 'merge duplicates <group>'");
+    Ok(())
+}
+
+async fn handle_merge_duplicates(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let arg = cmd
+        .strip_prefix("merge duplicates")
+        .unwrap_or_default()
+        .trim();
+    let n = match arg.parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            return Err("usage: merge duplicates <group number from find duplicates>".into());
+        }
+    };
+    let songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let groups = duplicate_groups(&songs);
+    let group = match groups.get(n - 1) {
+        Some(group) => group,
+        None => {
+            return Err(format!("no duplicate group {}, there are {}", n, groups.len()).into());
+        }
+    };
+    let ids: Vec<usize> = group.iter().map(|s| s.id).collect();
+    let removed: Vec<String> = ids[1..].iter().map(usize::to_string).collect();
+    info!(
+        "Keep song {} ({} - {}) and delete song(s) {}? Empty lyrics, cover and notes are taken from them. [y/N]",
+        ids[0],
+        group[0].title.trim(),
+        group[0].artist.trim(),
+        removed.join(", ")
+    );
+    swarm.behaviour_mut().confirmation = Some(PendingAction::MergeDuplicates(ids));
+    Ok(())
+}
+
+fn handle_respond_auto(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let auto_respond = match cmd.strip_prefix("respond auto").map(str::trim) {
+        Some("on") => true,
+        Some("off") => false,
+        _ => {
+            return Err("usage: respond auto on|off".into());
+        }
+    };
+    swarm.behaviour_mut().auto_respond = auto_respond;
+    if auto_respond {
+        info!("Answering list all requests");
+    } else {
+        info!("Ignoring list all requests, requests for this peer are still answered");
+    }
+    Ok(())
+}
+
+async fn handle_validate(cmd: &str) -> Result<()> {
+    let fix = match cmd.strip_prefix("validate").map(str::trim) {
+        Some("") => false,
+        Some("--fix") => true,
+        _ => {
+            return Err("usage: validate [--fix]".into());
+        }
+    };
+    let mut local_songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    let issues = validate_songs(&local_songs);
+    if issues.is_empty() {
+        info!("No problems found in {} songs", local_songs.len());
+        return Ok(());
+    }
+    info!("Found {} problem(s):", issues.len());
+    issues
+        .iter()
+        .for_each(|(id, problem)| info!("song {}: {}", id, problem));
+    if fix {
+        let fixed = fix_songs(&mut local_songs);
+        if fixed == 0 {
+            info!("Nothing could be fixed automatically");
+        } else if let Err(e) = write_local_songs(&local_songs).await {
+            return Err(format!("error writing fixed songs: {}", e).into());
+        } else {
+            let remaining = validate_songs(&local_songs).len();
+            info!("Fixed {} song(s), {} problem(s) remain", fixed, remaining);
+        }
+    }
+    Ok(())
+}
+
+/// One-shot cleanup turning legacy `explicit` values like "yes" or "1" into `true`/`false`.
+async fn handle_normalize_explicit() -> Result<()> {
+    let _guard = SAVE_LOCK.lock().await;
+    let content = match fs::read(&*STORAGE_FILE_PATH).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(StorageError::from_io(e, "reading", &STORAGE_FILE_PATH).into()),
+    };
+    // Songs are read as bools already, rewriting the file spells them "true"/"false".
+    let normalized = legacy_explicit_values(&content)
+        .iter()
+        .filter(|v| v.is_some())
+        .count();
+    if normalized > 0 {
+        let local_songs = match read_local_songs().await {
+            Ok(songs) => songs,
+            Err(e) => {
+                return Err(format!("error fetching local songs: {}", e).into());
+            }
+        };
+        if let Err(e) = write_local_songs(&local_songs).await {
+            return Err(format!("error writing normalized songs: {}", e).into());
+        }
+    }
+    info!("Normalized {} explicit value(s)", normalized);
+    Ok(())
+}
+
+async fn handle_share_song(cmd: &str) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("share song") {
+        let mut args = rest.trim().splitn(2, ' ');
+        let id_arg = args.next().unwrap_or_default();
+        let path = args.next().map(str::trim);
+        let id = parse_song_id(id_arg)?;
+        let song = match read_local_songs().await {
+            Ok(songs) => songs.into_iter().find(|s| s.id == id),
+            Err(e) => {
+                return Err(format!("error fetching local songs: {}", e).into());
+            }
+        };
+        let card = match song {
+            Some(song) => format_song_card(&song),
+            None => return Err(format!("Song with id {} not found", id).into()),
+        };
+        println!("{}", card);
+        if let Some(path) = path {
+            match fs::write(path, &card).await {
+                Ok(()) => info!("Wrote song {} to {}", id, path),
+                Err(e) => return Err(format!("error writing {}: {}", path, e).into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Turns a title into a file name part: letters, digits, `-` and `_` only, at most 60 characters.
+fn sanitize_file_name(title: &str) -> String {
+    let name: String = title
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .take(60)
+        .collect();
+    let name = name
+        .split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if name.is_empty() {
+        "untitled".to_owned()
+    } else {
+        name
+    }
+}
+
+async fn handle_export_lyrics(cmd: &str) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("export lyrics") {
+        let dir = Path::new(rest.trim());
+        if rest.trim().is_empty() {
+            return Err("usage: export lyrics <dir>".into());
+        }
+        let local_songs = match read_local_songs().await {
+            Ok(songs) => songs,
+            Err(e) => {
+                return Err(format!("error fetching local songs: {}", e).into());
+            }
+        };
+        if let Err(e) = fs::create_dir_all(dir).await {
+            return Err(format!("can't create {}: {}", dir.display(), e).into());
+        }
+        let mut names = HashSet::new();
+        let mut written = 0;
+        let mut skipped = 0;
+        for song in &local_songs {
+            if song.lyrics.trim().is_empty() {
+                skipped += 1;
+                continue;
+            }
+            let base = format!("{}-{}", song.id, sanitize_file_name(&song.title));
+            let mut name = format!("{}.txt", base);
+            let mut n = 2;
+            while !names.insert(name.clone()) {
+                name = format!("{}-{}.txt", base, n);
+                n += 1;
+            }
+            let path = dir.join(&name);
+            match fs::write(&path, format!("{}\n", song.lyrics.trim_end())).await {
+                Ok(()) => written += 1,
+                Err(e) => {
+                    return Err(format!("error writing {}: {}", path.display(), e).into());
+                }
+            }
+        }
+        info!(
+            "Wrote {} lyrics file(s) to {}, skipped {} song(s) without lyrics",
+            written,
+            dir.display(),
+            skipped
+        );
+    }
+    Ok(())
+}
+
+async fn handle_stats(cmd: &str) -> Result<()> {
+    let export_path = match cmd.strip_prefix("stats").map(str::trim) {
+        Some("") => None,
+        Some(rest) => match rest.strip_prefix("export ") {
+            Some(path) => Some(path.trim()),
+            None => {
+                return Err("usage: stats [export <path>]".into());
+            }
+        },
+        None => return Ok(()),
+    };
+    let stats = match read_local_songs().await {
+        Ok(songs) => compute_stats(&songs),
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    match export_path {
+        Some(path) => {
+            let json = serde_json::to_string_pretty(&stats).expect("can jsonify stats");
+            match fs::write(path, json).await {
+                Ok(()) => info!("Wrote stats to {}", path),
+                Err(e) => return Err(format!("error writing stats to {}: {}", path, e).into()),
+            }
+        }
+        None => {
+            info!("Songs: {}", stats.total);
+            info!("Public: {}", stats.public);
+            info!("Private: {}", stats.private);
+            info!(
+                "Explicit: {} ({:.0}%)",
+                stats.explicit,
+                stats.explicit_ratio * 100.0
+            );
+            info!(
+                "Average lyrics length: {:.0} characters",
+                stats.average_lyrics_chars
+            );
+            info!("Missing lyrics: {}", stats.missing_lyrics);
+            if let (Some(shortest), Some(longest)) = (&stats.shortest_title, &stats.longest_title) {
+                info!("Shortest title: {}", shortest);
+                info!("Longest title: {}", longest);
+            }
+            info!("Top artists:");
+            stats
+                .top_artists
+                .iter()
+                .for_each(|(artist, count)| info!("  {} ({})", artist, count));
+        }
+    }
+    Ok(())
+}
+
+fn handle_clone_peer(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("clone peer") {
+        let peer_id = rest.trim();
+        if peer_id.is_empty() {
+            return Err("usage: clone peer <peer id>".into());
+        }
+        if let Err(e) = PeerId::from_str(peer_id) {
+            return Err(format!("invalid peer id: {}, {}", peer_id, e).into());
+        }
+        info!(
+            "Copy all public songs of {} into your library as private songs? [y/N]",
+            peer_id
+        );
+        swarm.behaviour_mut().confirmation = Some(PendingAction::ClonePeer(peer_id.to_owned()));
+    }
+    Ok(())
+}
+
+fn handle_follow(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let peer_id = cmd.strip_prefix("follow").unwrap_or_default().trim();
+    let behaviour = swarm.behaviour_mut();
+    if peer_id.is_empty() {
+        if behaviour.followed.is_empty() {
+            info!("Not following any peers");
+            return Ok(());
+        }
+        info!("Followed peers:");
+        for (peer_id, followed) in &behaviour.followed {
+            let songs = match &followed.seen {
+                Some(seen) => format!("{} song(s)", seen.len()),
+                None => "no answer yet".to_owned(),
+            };
+            info!("  {} {}", peer_id, songs);
+        }
+        return Ok(());
+    }
+    if let Err(e) = PeerId::from_str(peer_id) {
+        return Err(format!("invalid peer id: {}, {}", peer_id, e).into());
+    }
+    if peer_id == PEER_ID.to_string() {
+        return Err("can't follow this node".into());
+    }
+    if behaviour.followed.contains_key(peer_id) {
+        info!("Already following {}", peer_id);
+        return Ok(());
+    }
+    behaviour.followed.insert(
+        peer_id.to_owned(),
+        FollowedPeer {
+            seen: None,
+            next_query: Instant::now(),
+        },
+    );
+    info!(
+        "Following {}, checking every {}s",
+        peer_id,
+        CONFIG.follow_interval.as_secs()
+    );
+    Ok(())
+}
+
+fn handle_unfollow(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let peer_id = cmd.strip_prefix("unfollow").unwrap_or_default().trim();
+    if peer_id.is_empty() {
+        return Err("usage: unfollow <peer id>".into());
+    }
+    match swarm.behaviour_mut().followed.remove(peer_id) {
+        Some(_) => info!("Stopped following {}", peer_id),
+        None => return Err(format!("Not following {}", peer_id).into()),
+    }
+    Ok(())
+}
+
+fn handle_verify_peer(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    let peer_id = cmd.strip_prefix("verify peer").unwrap_or_default().trim();
+    if peer_id.is_empty() {
+        return Err("usage: verify peer <peer id>".into());
+    }
+    let peer = match PeerId::from_str(peer_id) {
+        Ok(peer) => peer,
+        Err(e) => {
+            return Err(format!("invalid peer id: {}, {}", peer_id, e).into());
+        }
+    };
+    let mut connected = swarm.is_connected(&peer);
+    if connected && CONFIG.insecure {
+        warn!(
+            "SPOTIFY_BLOG_INSECURE is set, the plaintext transport doesn't authenticate peer ids"
+        );
+        connected = false;
+    }
+    start_query(
+        swarm,
+        ListMode::One(peer.to_string()),
+        QueryKind::Verify(connected),
+    )
+}
+
+async fn handle_confirmation(
+    action: PendingAction,
+    answer: &str,
+    swarm: &mut Swarm<SongBehaviour>,
+) -> Result<()> {
+    if let PendingAction::ResetIdentity = action {
+        if answer.trim() == "reset" {
+            return reset_identity();
+        }
+        info!("Cancelled, identity unchanged");
+        return Ok(());
+    }
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        info!("Cancelled");
+        return Ok(());
+    }
+    match action {
+        PendingAction::ClonePeer(peer_id) => {
+            start_query(swarm, ListMode::One(peer_id), QueryKind::Clone)
+        }
+        PendingAction::SeedPeer(peer_id) => {
+            start_query(swarm, ListMode::One(peer_id), QueryKind::Seed)
+        }
+        PendingAction::MergeDuplicates(ids) => {
+            let removed = merge_songs(&ids)
+                .await
+                .map_err(|e| format!("error merging songs: {}", e))?;
+            info!("Merged {} song(s) into song {}", removed.len(), ids[0]);
+            if CONFIG.announce_unpublish {
+                for song in removed.iter().filter(|s| s.public) {
+                    broadcast_unpublish(swarm, song.id);
+                }
+            }
+            Ok(())
+        }
+        PendingAction::ResetIdentity => unreachable!("handled above"),
+    }
+}
+
+/// The keypair from `identity.key` with `--persist-identity`, created on first use. A fresh one otherwise.
+fn load_identity() -> identity::Keypair {
+    if !CONFIG.persist_identity {
+        return identity::Keypair::generate_ed25519();
+    }
+    match std::fs::read(&*IDENTITY_FILE_PATH) {
+        Ok(mut bytes) => match identity::ed25519::Keypair::decode(&mut bytes) {
+            Ok(keypair) => return identity::Keypair::Ed25519(keypair),
+            Err(e) => error!(
+                "can't decode {}: {}, using a temporary identity",
+                IDENTITY_FILE_PATH.display(),
+                e
+            ),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let keypair = identity::ed25519::Keypair::generate();
+            if let Err(e) = save_identity(&keypair) {
+                error!("can't save {}: {}", IDENTITY_FILE_PATH.display(), e);
+            }
+            return identity::Keypair::Ed25519(keypair);
+        }
+        Err(e) => error!(
+            "can't read {}: {}, using a temporary identity",
+            IDENTITY_FILE_PATH.display(),
+            e
+        ),
+    }
+    identity::Keypair::generate_ed25519()
+}
+
+/// Creates and removes a scratch file, so a read-only data directory is reported at startup
+/// rather than when the first song is saved.
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+/// `identity.lock` next to the keypair, holding our pid while `--persist-identity` is in use.
+/// Removed again when the node shuts down.
+struct IdentityLock;
+
+impl IdentityLock {
+    fn acquire() -> Result<IdentityLock> {
+        let path = &*IDENTITY_LOCK_PATH;
+        for _ in 0..2 {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(mut file) => {
+                    // the pid is only informational, a failed write still holds the lock
+                    let _ = write!(file, "{}", std::process::id());
+                    return Ok(IdentityLock);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let owner = std::fs::read_to_string(path).unwrap_or_default();
+                    if !lock_owner_alive(owner.trim()) {
+                        warn!("removing stale {} (pid {})", path.display(), owner.trim());
+                        std::fs::remove_file(path)
+                            .map_err(|e| format!("can't remove stale {}: {}", path.display(), e))?;
+                        continue;
+                    }
+                    return Err(format!(
+                        "{} is already in use by another instance (pid {}), refusing to start",
+                        IDENTITY_FILE_PATH.display(),
+                        owner.trim()
+                    )
+                    .into());
+                }
+                Err(e) => return Err(format!("can't create {}: {}", path.display(), e).into()),
+            }
+        }
+        Err(format!("can't lock {}", path.display()).into())
+    }
+}
+
+impl Drop for IdentityLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&*IDENTITY_LOCK_PATH) {
+            error!("can't remove {}: {}", IDENTITY_LOCK_PATH.display(), e);
+        }
+    }
+}
+
+/// Whether the process that wrote a lock file is still running. Without `/proc` we can't
+/// tell, so the lock is trusted and has to be removed by hand after a crash.
+fn lock_owner_alive(pid: &str) -> bool {
+    if pid.parse::<u32>().is_err() {
+        return false;
+    }
+    if cfg!(target_os = "linux") {
+        Path::new("/proc").join(pid).exists()
+    } else {
+        true
+    }
+}
+
+fn save_identity(keypair: &identity::ed25519::Keypair) -> std::io::Result<()> {
+    std::fs::create_dir_all(&CONFIG.data_dir)?;
+    std::fs::write(&*IDENTITY_FILE_PATH, keypair.encode())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&*IDENTITY_FILE_PATH, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+fn handle_reset_identity(swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    if !CONFIG.persist_identity {
+        return Err("identity isn't persisted, every start already gets a new peer id (see --persist-identity)".into());
+    }
+    warn!("This replaces your keypair: peers will see you as a new peer id after a restart.");
+    warn!("Type 'reset' to confirm, anything else cancels.");
+    swarm.behaviour_mut().confirmation = Some(PendingAction::ResetIdentity);
+    Ok(())
+}
+
+fn reset_identity() -> Result<()> {
+    let keypair = identity::ed25519::Keypair::generate();
+    let new_peer_id = PeerId::from(identity::PublicKey::Ed25519(keypair.public()));
+    if let Err(e) = save_identity(&keypair) {
+        return Err(format!("can't save {}: {}", IDENTITY_FILE_PATH.display(), e).into());
+    }
+    info!("Old peer id: {}", *PEER_ID);
+    info!("New peer id: {}", new_peer_id);
+    info!("Restart to use it, then share the new peer id with anyone who had the old one");
+    Ok(())
+}
+
+fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+/// Largest cover file drawn inline, bigger images are only named.
+const MAX_INLINE_COVER: u64 = 5 * 1024 * 1024;
+
+/// Draws a local cover image with the iTerm2 or kitty graphics protocol. Other terminals,
+/// URLs and unreadable files keep just the path printed before.
+async fn show_cover(cover: &str) {
+    let kitty = std::env::var_os("KITTY_WINDOW_ID").is_some()
+        || std::env::var("TERM").is_ok_and(|t| t.contains("kitty"));
+    let iterm = std::env::var("TERM_PROGRAM").is_ok_and(|t| t == "iTerm.app");
+    if is_url(cover) || !(kitty || iterm) || !std::io::stdout().is_terminal() {
+        return;
+    }
+    match fs::metadata(cover).await {
+        Ok(meta) if meta.len() > MAX_INLINE_COVER => {
+            info!(
+                "cover is larger than {} bytes, not drawing it",
+                MAX_INLINE_COVER
+            );
+            return;
+        }
+        Ok(_) => (),
+        Err(e) => {
+            warn!("can't read cover {}: {}", cover, e);
+            return;
+        }
+    }
+    let image = match fs::read(cover).await {
+        Ok(image) => image,
+        Err(e) => {
+            warn!("can't read cover {}: {}", cover, e);
+            return;
+        }
+    };
+    let encoded = base64::encode(&image);
+    let mut out = std::io::stdout();
+    if iterm {
+        let _ = writeln!(
+            out,
+            "\x1b]1337;File=inline=1;size={}:{}\x07",
+            image.len(),
+            encoded
+        );
+    } else {
+        // kitty takes the image as PNG in chunks of at most 4096 base64 bytes
+        let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(4096).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let more = if i + 1 < chunks.len() { 1 } else { 0 };
+            let header = if i == 0 { "a=T,f=100," } else { "" };
+            let _ = write!(out, "\x1b_G{}m={};", header, more);
+            let _ = out.write_all(chunk);
+            let _ = write!(out, "\x1b\\");
+        }
+        let _ = writeln!(out);
+    }
+    let _ = out.flush();
+}
+
+async fn handle_show_song(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("show song") {
+        let id = parse_song_id(rest)?;
+        let song = match read_local_songs().await {
+            Ok(songs) => songs.into_iter().find(|s| s.id == id),
+            Err(e) => {
+                return Err(format!("error fetching local songs: {}", e).into());
+            }
+        };
+        match song {
+            Some(song) => {
+                info!(
+                    "Song {}: {} - {}",
+                    song.id,
+                    song.title.trim(),
+                    song.artist.trim()
+                );
+                info!("Explicit: {}", song.explicit);
+                info!("Public: {}", song.public);
+                if let Some(peers) = song.shared_with.as_ref().filter(|p| !p.is_empty()) {
+                    info!("Shared with: {}", peers.join(", "));
+                }
+                if let Some(origin) = &song.origin {
+                    info!("Origin: song {} of peer {}", origin.id, origin.peer);
+                }
+                if let Some(cover) = &song.cover {
+                    info!("Cover: {}", cover);
+                    if CONFIG.inline_covers {
+                        show_cover(cover).await;
+                    }
+                }
+                if !song.notes.is_empty() {
+                    info!("Notes (private, never shared):");
+                    song.notes.lines().for_each(|l| info!("  {}", l));
+                }
+                info!("Lyrics:");
+                song.lyrics.lines().for_each(|l| info!("  {}", l));
+                swarm.behaviour_mut().record_shown(&song);
+            }
+            None => return Err(format!("Song with id {} not found", id).into()),
+        }
+    }
+    Ok(())
+}
+
+fn handle_queries(swarm: &Swarm<SongBehaviour>) -> Result<()> {
+    let queries = &swarm.behaviour().queries;
+    if queries.is_empty() {
+        info!("No queries in flight");
+        return Ok(());
+    }
+    info!("Queries in flight ({}):", queries.len());
+    for query in queries {
+        let target = match &query.mode {
+            ListMode::ALL => "all peers",
+            ListMode::One(peer_id) => peer_id.as_str(),
+        };
+        info!(
+            "  {} to {}: {:.1}s of {}s, {} response(s)",
+            query.kind,
+            target,
+            query.started.elapsed().as_secs_f32(),
+            CONFIG.response_timeout.as_secs(),
+            query.responses.len()
+        );
+    }
+    Ok(())
+}
+
+fn handle_recent(swarm: &Swarm<SongBehaviour>) -> Result<()> {
+    let recent = &swarm.behaviour().recent;
+    if recent.is_empty() {
+        info!("No songs shown yet this session");
+        return Ok(());
+    }
+    info!("Recently shown songs:");
+    for (id, label, at) in recent.iter().rev() {
+        info!("  {}: {} ({}s ago)", id, label, at.elapsed().as_secs());
+    }
+    Ok(())
+}
+
+async fn handle_artists() -> Result<()> {
+    let local_songs = match read_local_songs().await {
+        Ok(songs) => songs,
+        Err(e) => {
+            return Err(format!("error fetching local songs: {}", e).into());
+        }
+    };
+    if local_songs.is_empty() {
+        info!("No songs in your library yet");
+        return Ok(());
+    }
+    let mut artists = artist_counts(&local_songs);
+    artists.sort_by_key(|(name, _)| name.to_lowercase());
+    info!("{} artist(s):", artists.len());
+    artists
+        .iter()
+        .for_each(|(name, count)| info!("  {} ({})", name, count));
+    Ok(())
+}
+
+async fn handle_raw_song(cmd: &str) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("raw song") {
+        let id = parse_song_id(rest)?;
+        let song = match read_local_songs().await {
+            Ok(songs) => songs.into_iter().find(|s| s.id == id),
+            Err(e) => {
+                return Err(format!("error fetching local songs: {}", e).into());
+            }
+        };
+        match song {
+            Some(song) => println!(
+                "{}",
+                serde_json::to_string_pretty(&song).expect("can jsonify song")
+            ),
+            None => return Err(format!("Song with id {} not found", id).into()),
+        }
+    }
+    Ok(())
+}
+
+async fn handle_note(cmd: &str) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("note") {
+        if rest.trim().is_empty() {
+            return Err("usage: note <id> <text> | note <id> --clear".into());
+        }
+        let mut args = rest.trim().splitn(2, ' ');
+        let id = parse_song_id(args.next().unwrap_or_default())?;
+        let text = args.next().map(str::trim).unwrap_or_default();
+        if text.is_empty() {
+            return Err("usage: note <id> <text> | note <id> --clear".into());
+        }
+        match add_note(id, text).await {
+            Ok(true) if text == "--clear" => info!("Cleared the notes of song {}", id),
+            Ok(true) => info!("Added a note to song {}", id),
+            Ok(false) => return Err(format!("Song with id {} not found", id).into()),
+            Err(e) => return Err(format!("error saving note for song {}: {}", id, e).into()),
+        }
+    }
+    Ok(())
+}
+
+/// Which field a search matched, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SearchMatch {
+    Title,
+    Artist,
+    Lyrics,
+}
+
+fn search_match(song: &Song, query: &str, lyrics: bool) -> Option<SearchMatch> {
+    if song.title.to_lowercase().contains(query) {
+        Some(SearchMatch::Title)
+    } else if song.artist.to_lowercase().contains(query) {
+        Some(SearchMatch::Artist)
+    } else if lyrics && song.lyrics.to_lowercase().contains(query) {
+        Some(SearchMatch::Lyrics)
+    } else {
+        None
+    }
+}
+
+async fn handle_search_songs(cmd: &str) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("search songs") {
+        let mut lyrics = false;
+        let words: Vec<&str> = rest
+            .split_whitespace()
+            .filter(|w| {
+                let flag = *w == "--lyrics";
+                lyrics |= flag;
+                !flag
+            })
+            .collect();
+        let query = words.join(" ").to_lowercase();
+        if query.is_empty() {
+            return Err("usage: search songs <query> [--lyrics]".into());
+        }
+        let local_songs = match read_local_songs().await {
+            Ok(songs) => songs,
+            Err(e) => {
+                return Err(format!("error fetching local songs: {}", e).into());
+            }
+        };
+        let mut found: Vec<(SearchMatch, Song)> = local_songs
+            .into_iter()
+            .filter_map(|s| search_match(&s, &query, lyrics).map(|m| (m, s)))
+            .collect();
+        found.sort_by_key(|(m, s)| (*m, s.id));
+        if found.is_empty() {
+            info!("No songs match {:?}", query);
+            return Ok(());
+        }
+        info!("{} song(s) match {:?}:", found.len(), query);
+        found.iter().for_each(|(m, s)| {
+            info!(
+                "  {}: {} by {} (matched {:?})",
+                s.id,
+                s.title.trim(),
+                s.artist.trim(),
+                m
+            )
+        });
+    }
+    Ok(())
+}
+
+/// Leaves the current topics and joins `name` instead.
+fn handle_topic(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("topic") {
+        let name = rest.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err("usage: topic <name>, without spaces".into());
+        }
+        let behaviour = swarm.behaviour_mut();
+        let old = behaviour.topic_names();
+        if old == [name] {
+            info!("Already on topic {}", name);
+            return Ok(());
+        }
+        for topic in behaviour.topics.drain(..).collect::<Vec<_>>() {
+            behaviour.floodsub.unsubscribe(topic);
+        }
+        let topic = Topic::new(name);
+        behaviour.floodsub.subscribe(topic.clone());
+        behaviour.topics.push(topic);
+        info!("Switched topic from {} to {}", old.join(", "), name);
+        warn!(
+            "Peers that stay on {} are no longer visible",
+            old.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn handle_seed_peer(cmd: &str, swarm: &mut Swarm<SongBehaviour>) -> Result<()> {
+    if let Some(rest) = cmd.strip_prefix("seed") {
+        let peer_id = rest.trim();
+        if peer_id.is_empty() {
+            return Err("usage: seed <peer id>".into());
+        }
+        if let Err(e) = PeerId::from_str(peer_id) {
+            return Err(format!("invalid peer id: {}, {}", peer_id, e).into());
+        }
+        info!(
+            "Save all public songs of {} and share them from your library? They stay attributed to the original peer. [y/N]",
+            peer_id
+        );
+        swarm.behaviour_mut().confirmation = Some(PendingAction::SeedPeer(peer_id.to_owned()));
+    }
+    Ok(())
+}
+
+fn handle_ping_all(swarm: &Swarm<SongBehaviour>) -> Result<()> {
+    let behaviour = swarm.behaviour();
+    let peers: HashSet<&PeerId> = behaviour
+        .mdns
+        .discovered_nodes()
+        .filter(|p| swarm.is_connected(p))
+        .collect();
+    if peers.is_empty() {
+        info!("No connected peers to ping");
+        return Ok(());
+    }
+    let mut rows: Vec<(&PeerId, Option<&std::result::Result<Duration, String>>)> = peers
+        .into_iter()
+        .map(|p| (p, behaviour.latencies.get(p)))
+        .collect();
+    // fastest first, then failures, then peers that haven't been pinged yet
+    rows.sort_by_key(|(_, latency)| match latency {
+        Some(Ok(rtt)) => (0, *rtt),
+        Some(Err(_)) => (1, Duration::ZERO),
+        None => (2, Duration::ZERO),
+    });
+    info!("Peer latencies:");
+    for (peer, latency) in rows {
+        let name = match behaviour.nicknames.get(&peer.to_string()) {
+            Some(nick) => format!("{} ({})", peer, nick),
+            None => peer.to_string(),
+        };
+        match latency {
+            Some(Ok(rtt)) => info!("  {} {} ms", name, rtt.as_millis()),
+            Some(Err(e)) => info!("  {} {}", name, e),
+            None => info!("  {} not pinged yet", name),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: usize, title: &str) -> Song {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "title": title,
+            "artist": "artist",
+            "lyrics": "lyrics",
+            "explicit": "false",
+            "public": true,
+        }))
+        .expect("valid song")
+    }
+
+    fn response(data: Songs) -> ListResponse {
+        ListResponse {
+            mode: ListMode::ALL,
+            data,
+            receiver: "receiver".to_owned(),
+            public_key: Vec::new(),
+            signature: Vec::new(),
+            compress: false,
+        }
+    }
+
+    #[test]
+    fn signed_response_verifies() {
+        let keys = identity::Keypair::generate_ed25519();
+        let mut resp = response(vec![song(0, "title")]);
+        resp.sign_with(&keys);
+        assert!(resp.verify(&PeerId::from(keys.public())));
+    }
+
+    #[test]
+    fn response_signed_by_another_key_is_rejected() {
+        let victim = identity::Keypair::generate_ed25519();
+        let forger = identity::Keypair::generate_ed25519();
+        let victim_id = PeerId::from(victim.public());
+        let mut resp = response(vec![song(0, "title")]);
+        resp.sign_with(&forger);
+        assert!(!resp.verify(&victim_id));
+        // claiming the victim's key doesn't help without its signature
+        resp.public_key = victim.public().into_protobuf_encoding();
+        assert!(!resp.verify(&victim_id));
+    }
+
+    #[test]
+    fn signature_is_sent_as_base64() {
+        let keys = identity::Keypair::generate_ed25519();
+        let mut resp = response(vec![song(0, "title")]);
+        resp.sign_with(&keys);
+        let json: serde_json::Value = serde_json::to_value(&resp).expect("can jsonify response");
+        assert_eq!(
+            json["signature"].as_str(),
+            Some(base64::encode(&resp.signature).as_str())
+        );
+        assert!(json["public_key"].is_string());
+        let received: ListResponse = serde_json::from_value(json).expect("can parse response");
+        assert!(received.verify(&PeerId::from(keys.public())));
+    }
+
+    #[test]
+    fn tampered_response_is_rejected() {
+        let keys = identity::Keypair::generate_ed25519();
+        let mut resp = response(vec![song(0, "title")]);
+        resp.sign_with(&keys);
+        resp.data.push(song(1, "injected"));
+        assert!(!resp.verify(&PeerId::from(keys.public())));
+    }
+
+    #[test]
+    fn unsigned_response_is_rejected() {
+        let keys = identity::Keypair::generate_ed25519();
+        let resp = response(vec![song(0, "title")]);
+        assert!(!resp.verify(&PeerId::from(keys.public())));
+        let mut resp = resp;
+        resp.sign_with(&keys);
+        resp.signature.clear();
+        assert!(!resp.verify(&PeerId::from(keys.public())));
+    }
+
+    #[test]
+    fn normalize_command_collapses_whitespace() {
+        assert_eq!(normalize_command("list songs  all"), "list songs all");
+        assert_eq!(normalize_command("list songs all\t"), "list songs all");
+        assert_eq!(normalize_command("   list peers"), "list peers");
+        assert_eq!(normalize_command(" \tlist\t songs \n"), "list songs");
+    }
+
+    #[test]
+    fn normalize_command_keeps_free_text() {
+        assert_eq!(
+            normalize_command("  create  song a  b|x|line  one\tend "),
+            "create song a  b|x|line  one\tend"
+        );
+        assert_eq!(
+            normalize_command("note   3  two  spaces\t"),
+            "note 3  two  spaces"
+        );
+        assert_eq!(normalize_command("notes  x"), "notes x");
+    }
+
+    #[test]
+    fn large_response_round_trips_gzipped() {
+        let mut resp = response((0..40).map(|id| song(id, "a longer title")).collect());
+        resp.compress = true;
+        let json = serde_json::to_vec(&resp).expect("can jsonify response");
+        assert!(json.len() >= COMPRESS_MIN_BYTES);
+        let wire = encode_response(&resp);
+        assert!(wire.starts_with(&GZIP_MAGIC));
+        assert!(wire.len() < json.len());
+        assert_eq!(
+            inflate_message(&wire).expect("inflates").as_ref(),
+            &json[..]
+        );
+    }
+
+    #[test]
+    fn small_response_stays_plain_json() {
+        let mut resp = response(vec![song(0, "title")]);
+        resp.compress = true;
+        let json = serde_json::to_vec(&resp).expect("can jsonify response");
+        assert!(json.len() < COMPRESS_MIN_BYTES);
+        let wire = encode_response(&resp);
+        assert_eq!(wire, json);
+        assert!(matches!(inflate_message(&wire), Ok(Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn oversized_inflation_is_rejected() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        let zeros = vec![0u8; 1024 * 1024];
+        for _ in 0..=MAX_INFLATED_BYTES / zeros.len() as u64 {
+            encoder.write_all(&zeros).expect("can compress");
+        }
+        let bomb = encoder.finish().expect("can compress");
+        let err = inflate_message(&bomb).expect_err("too large");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn explicit_field_is_normalized() {
+        assert_eq!(explicit_field(Some("yes"), false), Ok(true));
+        assert_eq!(explicit_field(Some(" N "), true), Ok(false));
+        assert_eq!(explicit_field(Some(""), true), Ok(true));
+        assert_eq!(explicit_field(None, false), Ok(false));
+        assert!(explicit_field(Some("maybe"), false).is_err());
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflow() {
+        assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(1_209_600)));
+        assert!(parse_duration("99999999999999999w").is_err());
+        assert!(parse_duration("18446744073709551615m").is_err());
+    }
+
+    #[test]
+    fn near_duplicates_are_conservative() {
+        let with = |title: &str, artist: &str, explicit: bool| {
+            let mut s = song(0, title);
+            s.artist = artist.to_owned();
+            s.explicit = explicit;
+            s
+        };
+        let jude = with("Hey Jude", "The Beatles", false);
+        assert!(near_duplicates(
+            &jude,
+            &with("hey jude! ", "the  beatles", false)
+        ));
+        assert!(near_duplicates(
+            &with("Yesterday Once More", "Carpenters", false),
+            &with("Yesterday Once Mor", "Carpenters", false)
+        ));
+        assert!(!near_duplicates(
+            &jude,
+            &with("Hey Jude", "The Beatles", true)
+        ));
+        assert!(!near_duplicates(&jude, &with("Hey Jude", "Other", false)));
+        assert!(!near_duplicates(
+            &with("Part 1", "Band", false),
+            &with("Part 2", "Band", false)
+        ));
+        assert!(!near_duplicates(
+            &with("Chapter 10", "Band", false),
+            &with("Chapter 11", "Band", false)
+        ));
+    }
+
+    #[test]
+    fn commands_match_whole_words() {
+        assert!(is_command("stats", "stats"));
+        assert!(is_command("stats export s.json", "stats"));
+        assert!(!is_command("statsfoo", "stats"));
+        assert!(is_command("set nick x", "set nick"));
+        assert!(!is_command("set nickname x", "set nick"));
+    }
+
+    #[test]
+    fn legacy_explicit_values_are_read_as_bools() {
+        let stored = serde_json::json!([
+            {"id": 0, "title": "a", "artist": "b", "lyrics": "c", "explicit": "yes", "public": true},
+            {"id": 1, "title": "a", "artist": "b", "lyrics": "c", "explicit": true, "public": true},
+            {"id": 2, "title": "a", "artist": "b", "lyrics": "c", "explicit": "maybe", "public": true},
+            {"id": 3, "title": "a", "artist": "b", "lyrics": "c", "explicit": "false", "public": true},
+        ]);
+        let songs: Songs = serde_json::from_value(stored.clone()).expect("valid songs");
+        let explicit: Vec<bool> = songs.iter().map(|s| s.explicit).collect();
+        assert_eq!(explicit, [true, true, false, false]);
+        assert_eq!(
+            serde_json::to_value(&songs[0]).expect("serializable")["explicit"],
+            "true"
+        );
+        let content = serde_json::to_vec(&stored).expect("serializable");
+        assert_eq!(
+            legacy_explicit_values(&content),
+            [Some("yes".to_owned()), None, Some("maybe".to_owned()), None]
+        );
+    }
+}